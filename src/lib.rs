@@ -5,16 +5,241 @@
 //! which is decremented every time a defer is executed. When the counter reaches 0, the
 //! provided function is executed.
 
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::io::{self, BufWriter, Write};
+use std::marker::PhantomData;
+use std::ops::Add;
+use std::panic;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, Once, PoisonError};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
 /// The Defer struct provides defer functionality for synchronous code.
 /// It takes a function which is run when the Defer struct is dropped.
+///
+/// Like any other local, Defers bound in the same scope are dropped in reverse
+/// order of declaration, so the last deferred function runs first, as in Go.
+/// The combinators which hold several functions ([`Defer::compose_all`],
+/// [`DeferStack`], [`FixedDeferStack`]) follow the same order.
 pub struct Defer<F: FnOnce()> {
     f: Option<F>,
+    #[cfg(feature = "backtrace")]
+    backtrace: Option<std::backtrace::Backtrace>,
 }
 
 impl<F: FnOnce()> Defer<F> {
     /// Creates a new Defer instance with the provided function.
     pub fn new(f: F) -> Defer<F> {
-        Defer { f: Some(f) }
+        Defer {
+            f: Some(f),
+            #[cfg(feature = "backtrace")]
+            backtrace: None,
+        }
+    }
+
+    /// Creates a new Defer instance which captures a backtrace of where it was
+    /// created, to help track down guards which are leaked or run unexpectedly.
+    ///
+    /// The backtrace is captured regardless of `RUST_BACKTRACE`. A leaked guard
+    /// can't report itself, since it is never dropped, so keep the guard around
+    /// (or log its backtrace up front) where it has to be inspected.
+    #[cfg(feature = "backtrace")]
+    pub fn new_traced(f: F) -> Defer<F> {
+        Defer {
+            f: Some(f),
+            backtrace: Some(std::backtrace::Backtrace::force_capture()),
+        }
+    }
+
+    /// Returns the backtrace captured by [`Defer::new_traced`], if any.
+    #[cfg(feature = "backtrace")]
+    pub fn creation_backtrace(&self) -> Option<&std::backtrace::Backtrace> {
+        self.backtrace.as_ref()
+    }
+
+    /// Disarms the Defer without running the stored function.
+    pub fn cancel(&mut self) {
+        self.f = None;
+    }
+
+    /// Runs the stored function right away instead of when the Defer is dropped.
+    pub fn now(&mut self) {
+        if let Some(f) = self.f.take() {
+            f()
+        }
+    }
+
+    /// Returns true if the stored function has neither been run nor cancelled.
+    pub fn is_armed(&self) -> bool {
+        self.f.is_some()
+    }
+
+    /// Turns the Defer into a future which runs the stored function when it is
+    /// polled, so asynchronous code can decide exactly where the cleanup happens.
+    /// A future which is dropped without being awaited never runs the function.
+    #[cfg(feature = "async")]
+    pub fn into_future(mut self) -> impl std::future::Future<Output = ()> {
+        let f = self.f.take();
+        async move {
+            if let Some(f) = f {
+                f()
+            }
+        }
+    }
+}
+
+// Constructors which build the function themselves. `fn()` only fills in the
+// type parameter so they can be called as `Defer::...`.
+impl Defer<fn()> {
+    /// Creates a Defer which can be moved to another thread.
+    /// The function is run on whichever thread drops the Defer.
+    ///
+    /// Any Defer whose function is `Send` is `Send` itself, this constructor
+    /// only requires it up front, so a capture which can't cross threads is
+    /// reported here instead of at the `thread::spawn` call.
+    pub fn new_send(f: impl FnOnce() + Send + 'static) -> Defer<impl FnOnce() + Send + 'static> {
+        Defer::new(f)
+    }
+
+    /// Creates a Defer which drops the provided value when it is dropped, or when
+    /// [`Defer::now`] is called. This puts the value's drop in line with other
+    /// guards, rather than at the end of the scope it was declared in.
+    pub fn drop_value<T>(value: T) -> Defer<impl FnOnce()> {
+        Defer::new(move || drop(value))
+    }
+
+    /// Creates a Defer which releases the provided lock guard (e.g. a `MutexGuard`
+    /// or an `RwLockWriteGuard`) when it is dropped, or when [`Defer::now`] is
+    /// called. The Defer borrows the lock just like the guard does.
+    pub fn unlock<G>(guard: G) -> Defer<impl FnOnce()> {
+        Defer::drop_value(guard)
+    }
+
+    /// Creates a Defer which runs all of the provided functions, in reverse order,
+    /// when it is dropped. Unlike a [`DeferStack`] the set of functions is fixed
+    /// once the Defer has been created.
+    pub fn compose_all<'a>(closures: Vec<Box<dyn FnOnce() + 'a>>) -> Defer<impl FnOnce() + 'a> {
+        Defer::new(move || {
+            for f in closures.into_iter().rev() {
+                f()
+            }
+        })
+    }
+
+    /// Creates a Defer which owns some state and passes it to the provided
+    /// function by `&mut` when dropped, sparing the captures a `RefCell`.
+    /// The state can be read back via [`StateDefer::take_state`].
+    pub fn new_mut<T>(state: T, f: impl FnOnce(&mut T)) -> StateDefer<T, impl FnOnce(&mut T)> {
+        StateDefer {
+            state: Some(state),
+            f: Some(f),
+        }
+    }
+
+    /// Creates a Defer which picks its cleanup based on how the scope ended.
+    /// If `success_flag` is set when the Defer is dropped `on_ok` is run,
+    /// otherwise `on_err` is run.
+    ///
+    /// Set the flag as the last statement of the guarded scope, so an early
+    /// return via `?` or a panic both end up in `on_err`.
+    pub fn on_outcome(
+        success_flag: Arc<AtomicBool>,
+        on_ok: impl FnOnce(),
+        on_err: impl FnOnce(),
+    ) -> Defer<impl FnOnce()> {
+        Defer::new(move || {
+            if success_flag.load(Ordering::SeqCst) {
+                on_ok()
+            } else {
+                on_err()
+            }
+        })
+    }
+
+    /// Creates a Defer which runs `setup` right away and `teardown` when it is
+    /// dropped.
+    pub fn tap(setup: impl FnOnce(), teardown: impl FnOnce()) -> Defer<impl FnOnce()> {
+        setup();
+        Defer::new(teardown)
+    }
+
+    /// Creates a Defer from a fallible setup, which is run right away. When the
+    /// Defer is dropped the outcome of the setup is passed to `on_ok` if it
+    /// succeeded, and to `on_err` if it failed.
+    pub fn from_result_fn<T, E>(
+        setup: impl FnOnce() -> Result<T, E>,
+        on_ok: impl FnOnce(T),
+        on_err: impl FnOnce(E),
+    ) -> Defer<impl FnOnce()> {
+        let result = setup();
+        Defer::new(move || match result {
+            Ok(value) => on_ok(value),
+            Err(error) => on_err(error),
+        })
+    }
+
+    /// Creates a guard which owns the provided writer and flushes it when
+    /// dropped. Writes go through the guard, which counts the bytes written;
+    /// the returned [`WriteReport`] exposes the count, and any flush error,
+    /// once the guard has been dropped.
+    pub fn flush_writer<W: Write>(writer: BufWriter<W>) -> (WriterDefer<W>, WriteReport) {
+        let report = WriteReport::default();
+        let guard = WriterDefer {
+            writer,
+            written: 0,
+            report: report.clone(),
+        };
+        (guard, report)
+    }
+
+    /// Creates a Defer which only runs the provided function if at least `min`
+    /// has elapsed between its creation and its drop.
+    pub fn if_elapsed(min: Duration, f: impl FnOnce()) -> Defer<impl FnOnce()> {
+        let start = Instant::now();
+        Defer::new(move || {
+            if start.elapsed() >= min {
+                f()
+            }
+        })
+    }
+
+    /// Creates a Defer which only runs the provided function if it is dropped
+    /// while the thread is unwinding from a panic. The function receives the
+    /// panic message, if one could be recovered.
+    ///
+    /// `Drop` has no access to the panic payload, so the message is recorded
+    /// by a panic hook which this constructor installs, once, in front of the
+    /// existing one. The message is therefore `None` if the payload isn't a
+    /// string, if a hook set afterwards doesn't call the previous one, or if
+    /// the unwind skipped the hooks, as with [`std::panic::resume_unwind`].
+    ///
+    /// The recorded message is cleared once no such Defer is left on the
+    /// thread, so it doesn't outlive the panic. A panic caught while one is
+    /// alive, followed by a `resume_unwind` in the same scope, still reports
+    /// the caught panic's message.
+    pub fn on_unwind_with(f: impl FnOnce(Option<&str>)) -> Defer<impl FnOnce()> {
+        install_panic_message_hook();
+        let reader = UnwindReader::new();
+        Defer::new(move || {
+            let _reader = reader;
+            if thread::panicking() {
+                PANIC_MESSAGE.with(|message| f(message.borrow().as_deref()))
+            }
+        })
+    }
+
+    /// Creates a Defer which traces the enclosing scope: it logs `enter <name>`
+    /// right away and `exit <name>`, along with the time spent in the scope,
+    /// when it is dropped. Both lines are logged at trace level.
+    #[cfg(feature = "log")]
+    pub fn trace_scope(name: &'static str) -> Defer<impl FnOnce()> {
+        log::trace!("enter {}", name);
+        let start = Instant::now();
+        Defer::new(move || log::trace!("exit {} after {:?}", name, start.elapsed()))
     }
 }
 
@@ -27,57 +252,1817 @@ impl<F: FnOnce()> Drop for Defer<F> {
     }
 }
 
+/// Combines two Defers into one which runs both functions, the right-hand one
+/// first, as if the two had been declared in order. The operands are moved into
+/// the combined Defer, and one which had already been cancelled or run is skipped.
+///
+/// The combined function is boxed so its type can be named, which requires
+/// both functions to be `'static`. Use [`Defer::compose_all`] for borrowing ones.
+impl<F: FnOnce() + 'static, G: FnOnce() + 'static> Add<Defer<G>> for Defer<F> {
+    type Output = Defer<Box<dyn FnOnce()>>;
+
+    fn add(self, rhs: Defer<G>) -> Self::Output {
+        Defer::new(Box::new(move || {
+            drop(rhs);
+            drop(self);
+        }))
+    }
+}
+
+/// The DynDefer struct is the type-erased counterpart to [`Defer`]. Its function is
+/// boxed, so the type can be named, e.g. as the type of a struct field.
+pub struct DynDefer {
+    f: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl DynDefer {
+    /// Creates a new DynDefer instance with the provided function.
+    pub fn new(f: impl FnOnce() + Send + 'static) -> DynDefer {
+        DynDefer {
+            f: Some(Box::new(f)),
+        }
+    }
+
+    /// Disarms the DynDefer without running the stored function.
+    pub fn cancel(&mut self) {
+        self.f = None;
+    }
+
+    /// Runs the stored function right away instead of when the DynDefer is dropped.
+    pub fn now(&mut self) {
+        if let Some(f) = self.f.take() {
+            f()
+        }
+    }
+
+    /// Returns true if the stored function has neither been run nor cancelled.
+    pub fn is_armed(&self) -> bool {
+        self.f.is_some()
+    }
+}
+
+impl Drop for DynDefer {
+    /// Runs the stored function when the DynDefer struct is dropped.
+    fn drop(&mut self) {
+        self.now()
+    }
+}
+
+/// A function routed back to the thread it was deferred on, see [`ThreadDefer`].
+type Routed = Box<dyn FnOnce() + Send>;
+
+/// The installed [`ThreadRouter`]s. There are few of them, so a list will do, and
+/// ThreadId is not ordered anyway.
+static ROUTERS: Mutex<Vec<(ThreadId, Sender<Routed>)>> = Mutex::new(Vec::new());
+
+fn lock_routers() -> MutexGuard<'static, Vec<(ThreadId, Sender<Routed>)>> {
+    ROUTERS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The ThreadDefer struct provides defer functionality for functions which must run
+/// on the thread which created the ThreadDefer, e.g. to free thread-affine FFI
+/// handles. When it is dropped on that thread, the function runs right away.
+/// When it is dropped on any other thread, the function is sent to the
+/// [`ThreadRouter`] of the origin thread, which runs it there.
+///
+/// Dropping a ThreadDefer off-thread panics if the origin thread has no router
+/// installed, as running the function on the wrong thread is never an option.
+pub struct ThreadDefer {
+    origin: ThreadId,
+    f: Option<Routed>,
+}
+
+impl ThreadDefer {
+    /// Creates a new ThreadDefer instance with the provided function, bound to the
+    /// current thread.
+    pub fn new(f: impl FnOnce() + Send + 'static) -> ThreadDefer {
+        ThreadDefer {
+            origin: thread::current().id(),
+            f: Some(Box::new(f)),
+        }
+    }
+
+    /// Returns the id of the thread the stored function will run on.
+    pub fn origin(&self) -> ThreadId {
+        self.origin
+    }
+}
+
+impl Drop for ThreadDefer {
+    /// Runs the stored function, or routes it to the origin thread.
+    fn drop(&mut self) {
+        let f = match self.f.take() {
+            Some(f) => f,
+            None => return,
+        };
+        if thread::current().id() == self.origin {
+            return f();
+        }
+        let routed = lock_routers()
+            .iter()
+            .find(|(id, _)| *id == self.origin)
+            .map(|(_, sender)| sender.send(f).is_ok());
+        if routed != Some(true) {
+            panic!("ThreadDefer dropped off-thread, but its origin thread has no ThreadRouter");
+        }
+    }
+}
+
+/// The ThreadRouter struct receives the functions of [`ThreadDefer`]s created on
+/// the current thread but dropped elsewhere. The routed functions are run by
+/// polling the router, and any still pending are run when it is dropped.
+pub struct ThreadRouter {
+    id: ThreadId,
+    receiver: Receiver<Routed>,
+    // The router has to stay on the thread it was installed on.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl ThreadRouter {
+    /// Installs a router for the current thread.
+    ///
+    /// Panics if the current thread already has one.
+    pub fn install() -> ThreadRouter {
+        let id = thread::current().id();
+        let (sender, receiver) = mpsc::channel();
+        let mut routers = lock_routers();
+        assert!(
+            routers.iter().all(|(installed, _)| *installed != id),
+            "a ThreadRouter is already installed on this thread"
+        );
+        routers.push((id, sender));
+        ThreadRouter {
+            id,
+            receiver,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Runs all functions routed to this thread so far, returning how many ran.
+    pub fn run_pending(&self) -> usize {
+        self.receiver.try_iter().map(|f| f()).count()
+    }
+
+    /// Waits up to `timeout` for a function to be routed to this thread and runs
+    /// it. Returns true if one ran.
+    pub fn run_timeout(&self, timeout: Duration) -> bool {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(f) => {
+                f();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl Drop for ThreadRouter {
+    /// Uninstalls the router and runs the functions still pending.
+    fn drop(&mut self) {
+        lock_routers().retain(|(id, _)| *id != self.id);
+        self.run_pending();
+    }
+}
+
+/// The FlushDefer struct provides defer functionality for functions which may also
+/// be run early. It takes a function which can be triggered any number of times,
+/// and which is run when the FlushDefer struct is dropped only if it never was.
+pub struct FlushDefer<F: FnMut()> {
+    f: F,
+    triggered: bool,
+}
+
+impl<F: FnMut()> FlushDefer<F> {
+    /// Creates a new FlushDefer instance with the provided function.
+    pub fn new(f: F) -> FlushDefer<F> {
+        FlushDefer {
+            f,
+            triggered: false,
+        }
+    }
+
+    /// Runs the stored function right away. Once triggered, the function is no
+    /// longer run when the FlushDefer struct is dropped.
+    pub fn trigger(&mut self) {
+        (self.f)();
+        self.triggered = true;
+    }
+
+    /// Returns true if the function has been triggered at least once.
+    pub fn is_triggered(&self) -> bool {
+        self.triggered
+    }
+}
+
+impl<F: FnMut()> Drop for FlushDefer<F> {
+    /// Runs the stored function, unless it has been triggered before.
+    fn drop(&mut self) {
+        if !self.triggered {
+            (self.f)()
+        }
+    }
+}
+
+/// The OnceDefer struct provides defer functionality for functions shared between
+/// several owners. It takes a function which is run at most once: either when any
+/// of the clones calls [`OnceDefer::trigger`], or when the last clone is dropped.
+pub struct OnceDefer<F: FnOnce()> {
+    inner: Arc<OnceInner<F>>,
+}
+
+struct OnceInner<F: FnOnce()> {
+    once: Once,
+    f: Mutex<Option<F>>,
+}
+
+impl<F: FnOnce()> OnceInner<F> {
+    fn run(&self) {
+        // Forced, so a function which panicked poisons neither later triggers
+        // nor the final drop: it has been taken, so it isn't run again.
+        self.once.call_once_force(|_| {
+            let f = self.f.lock().unwrap_or_else(PoisonError::into_inner).take();
+            if let Some(f) = f {
+                f()
+            }
+        });
+    }
+}
+
+impl<F: FnOnce()> OnceDefer<F> {
+    /// Creates a new OnceDefer instance with the provided function.
+    pub fn new(f: F) -> OnceDefer<F> {
+        OnceDefer {
+            inner: Arc::new(OnceInner {
+                once: Once::new(),
+                f: Mutex::new(Some(f)),
+            }),
+        }
+    }
+
+    /// Runs the stored function right away, unless it has already been run.
+    pub fn trigger(&self) {
+        self.inner.run()
+    }
+
+    /// Returns true if the stored function has been run. A function which
+    /// panicked only counts as run once the OnceDefer has been triggered again.
+    pub fn is_done(&self) -> bool {
+        self.inner.once.is_completed()
+    }
+}
+
+impl<F: FnOnce()> Clone for OnceDefer<F> {
+    fn clone(&self) -> Self {
+        OnceDefer {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<F: FnOnce()> Drop for OnceInner<F> {
+    /// Runs the stored function once the last OnceDefer is dropped, unless it
+    /// has already been run.
+    fn drop(&mut self) {
+        self.run()
+    }
+}
+
+/// The LazyDefer struct provides defer functionality for a lazily created resource.
+/// It takes a function which is passed the resource when the LazyDefer struct is
+/// dropped, but only if the resource was created in the first place.
+pub struct LazyDefer<T, F: FnOnce(T)> {
+    value: Option<T>,
+    teardown: Option<F>,
+}
+
+impl<T, F: FnOnce(T)> LazyDefer<T, F> {
+    /// Creates a new LazyDefer instance with the provided teardown function.
+    pub fn new(teardown: F) -> LazyDefer<T, F> {
+        LazyDefer {
+            value: None,
+            teardown: Some(teardown),
+        }
+    }
+
+    /// Returns the resource, creating it via `init` if this is the first access.
+    pub fn get_or_init(&mut self, init: impl FnOnce() -> T) -> &mut T {
+        self.value.get_or_insert_with(init)
+    }
+
+    /// Returns true if the resource has been created.
+    pub fn is_initialized(&self) -> bool {
+        self.value.is_some()
+    }
+}
+
+impl<T, F: FnOnce(T)> Drop for LazyDefer<T, F> {
+    /// Passes the resource to the teardown function, if it was ever created.
+    fn drop(&mut self) {
+        if let (Some(value), Some(teardown)) = (self.value.take(), self.teardown.take()) {
+            teardown(value)
+        }
+    }
+}
+
+thread_local! {
+    /// The message of the last panic on this thread, see [`Defer::on_unwind_with`].
+    static PANIC_MESSAGE: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// The number of live [`UnwindReader`]s on this thread.
+    static UNWIND_READERS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts the live readers of [`PANIC_MESSAGE`], i.e. the functions of Defers
+/// created via [`Defer::on_unwind_with`]: a message recorded while there are
+/// none, or left behind by the last of them, belongs to a panic which is over.
+struct UnwindReader;
+
+impl UnwindReader {
+    fn new() -> UnwindReader {
+        UNWIND_READERS.with(|readers| {
+            if readers.get() == 0 && !thread::panicking() {
+                PANIC_MESSAGE.with(|m| *m.borrow_mut() = None);
+            }
+            readers.set(readers.get() + 1);
+        });
+        UnwindReader
+    }
+}
+
+impl Drop for UnwindReader {
+    fn drop(&mut self) {
+        UNWIND_READERS.with(|readers| {
+            readers.set(readers.get() - 1);
+            if readers.get() == 0 {
+                PANIC_MESSAGE.with(|m| *m.borrow_mut() = None);
+            }
+        });
+    }
+}
+
+/// Chains a panic hook recording the panic message in front of the current one.
+fn install_panic_message_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let payload = info.payload();
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned());
+            PANIC_MESSAGE.with(|m| *m.borrow_mut() = message);
+            previous(info)
+        }));
+    });
+}
+
+/// The functions of all live [`RegisteredDefer`]s, keyed in order of registration.
+type Registry = BTreeMap<u64, Box<dyn FnOnce() + Send>>;
+
+static REGISTRY: Mutex<Registry> = Mutex::new(BTreeMap::new());
+static NEXT_REGISTRY_ID: AtomicU64 = AtomicU64::new(0);
+static SCOPE_HOOK_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The RegisteredDefer struct provides defer functionality which also takes part in
+/// the scope hook, see [`install_scope_hook`]. It takes a function which is run
+/// when the RegisteredDefer struct is dropped, or when a panic reaches the hook,
+/// whichever comes first.
+pub struct RegisteredDefer {
+    id: u64,
+}
+
+impl RegisteredDefer {
+    /// Creates a new RegisteredDefer instance with the provided function.
+    pub fn new(f: impl FnOnce() + Send + 'static) -> RegisteredDefer {
+        let id = NEXT_REGISTRY_ID.fetch_add(1, Ordering::SeqCst);
+        lock_registry().insert(id, Box::new(f));
+        RegisteredDefer { id }
+    }
+}
+
+impl Drop for RegisteredDefer {
+    /// Runs the stored function, unless the scope hook already has.
+    fn drop(&mut self) {
+        let f = lock_registry().remove(&self.id);
+        if let Some(f) = f {
+            f()
+        }
+    }
+}
+
+/// Installs a panic hook which runs the functions of all live [`RegisteredDefer`]s,
+/// newest first, before handing over to the previously installed hook.
+///
+/// This is global state, with the tradeoffs that come with it:
+/// - the functions run on the panicking thread, including those of guards which
+///   live on other threads, as the panic may be about to take the process down;
+/// - they run before the panic message is printed, and a panic inside one of
+///   them aborts the process;
+/// - every RegisteredDefer goes through a global lock on creation and drop.
+///
+/// The hook is only chained in once. [`uninstall_scope_hook`] turns it back into
+/// a pass-through instead of removing it, so hooks installed after it are kept.
+pub fn install_scope_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if SCOPE_HOOK_ENABLED.load(Ordering::SeqCst) {
+                flush_registry();
+            }
+            previous(info)
+        }));
+    });
+    SCOPE_HOOK_ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stops the hook installed by [`install_scope_hook`] from running any functions.
+pub fn uninstall_scope_hook() {
+    SCOPE_HOOK_ENABLED.store(false, Ordering::SeqCst);
+}
+
+fn lock_registry() -> MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Runs all registered functions, newest first.
+fn flush_registry() {
+    loop {
+        // Take one function at a time, so none of them runs under the lock.
+        let f = lock_registry().pop_last();
+        match f {
+            Some((_, f)) => f(),
+            None => break,
+        }
+    }
+}
+
+/// The StateDefer struct provides defer functionality for functions which mutate
+/// state owned by the defer itself. It is created via [`Defer::new_mut`].
+pub struct StateDefer<T, F: FnOnce(&mut T)> {
+    state: Option<T>,
+    f: Option<F>,
+}
+
+impl<T, F: FnOnce(&mut T)> StateDefer<T, F> {
+    /// Returns the state, e.g. to prepare it before the function runs.
+    pub fn state_mut(&mut self) -> &mut T {
+        self.state.as_mut().unwrap()
+    }
+
+    /// Runs the stored function, unless it has already been run, and returns
+    /// the state it left behind.
+    pub fn take_state(mut self) -> T {
+        self.run();
+        self.state.take().unwrap()
+    }
+
+    fn run(&mut self) {
+        if let (Some(state), Some(f)) = (self.state.as_mut(), self.f.take()) {
+            f(state)
+        }
+    }
+}
+
+impl<T, F: FnOnce(&mut T)> Drop for StateDefer<T, F> {
+    /// Runs the stored function when the StateDefer struct is dropped.
+    fn drop(&mut self) {
+        self.run()
+    }
+}
+
+/// The WriterDefer struct provides defer functionality for flushing a writer.
+/// It is created via [`Defer::flush_writer`], and writes to the wrapped writer
+/// until it is dropped.
+pub struct WriterDefer<W: Write> {
+    writer: BufWriter<W>,
+    written: u64,
+    report: WriteReport,
+}
+
+impl<W: Write> Write for WriterDefer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for WriterDefer<W> {
+    /// Flushes the writer and records the outcome in the [`WriteReport`].
+    fn drop(&mut self) {
+        let flushed = self.writer.flush();
+        let mut report = self.report.lock();
+        report.written = Some(self.written);
+        report.error = flushed.err();
+    }
+}
+
+/// Holds the outcome of a [`WriterDefer`], so it can be inspected once the
+/// guard has been dropped.
+#[derive(Clone, Default)]
+pub struct WriteReport {
+    inner: Arc<Mutex<WriteOutcome>>,
+}
+
+#[derive(Default)]
+struct WriteOutcome {
+    written: Option<u64>,
+    error: Option<io::Error>,
+}
+
+impl WriteReport {
+    /// Returns how many bytes were written through the guard, or `None` if it
+    /// hasn't been dropped yet. If the final flush failed, not all of them
+    /// reached the underlying writer, see [`WriteReport::take_flush_error`].
+    pub fn bytes_written(&self) -> Option<u64> {
+        self.lock().written
+    }
+
+    /// Takes the error of the final flush, if it failed.
+    pub fn take_flush_error(&self) -> Option<io::Error> {
+        self.lock().error.take()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, WriteOutcome> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The DeferStack struct provides defer functionality for any number of functions.
+/// The registered functions are run in reverse order of registration when the
+/// DeferStack struct is dropped.
+///
+/// `E` is the error type of the functions registered via [`DeferStack::push_try`].
+/// Stacks which only hold infallible functions leave it at its default.
+pub struct DeferStack<'a, E = Infallible> {
+    fs: Vec<Entry<'a, E>>,
+}
+
+/// A function registered with a DeferStack, along with its optional tag.
+struct Entry<'a, E> {
+    tag: Option<&'static str>,
+    f: Box<dyn FnOnce() -> Result<(), E> + 'a>,
+}
+
+impl<'a> DeferStack<'a> {
+    /// Creates a new, empty DeferStack instance.
+    pub fn new() -> DeferStack<'a> {
+        DeferStack { fs: Vec::new() }
+    }
+
+    /// Creates a new, empty DeferStack instance with room for `capacity` functions
+    /// before it needs to reallocate.
+    pub fn with_capacity(capacity: usize) -> DeferStack<'a> {
+        DeferStack {
+            fs: Vec::with_capacity(capacity),
+        }
+    }
+}
+
+impl<'a, E> DeferStack<'a, E> {
+    /// Creates a new, empty DeferStack instance for functions which fail with `E`.
+    pub fn new_fallible() -> DeferStack<'a, E> {
+        DeferStack { fs: Vec::new() }
+    }
+
+    /// Creates a new, empty DeferStack instance for functions which fail with `E`,
+    /// with room for `capacity` functions before it needs to reallocate.
+    pub fn with_capacity_fallible(capacity: usize) -> DeferStack<'a, E> {
+        DeferStack {
+            fs: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Registers a function to be run when the DeferStack struct is dropped.
+    pub fn push(&mut self, f: impl FnOnce() + 'a) {
+        self.fs.push(Entry {
+            tag: None,
+            f: Box::new(move || {
+                f();
+                Ok(())
+            }),
+        });
+    }
+
+    /// Registers a fallible function, see [`DeferStack::run_until_error`]. When
+    /// it is run any other way, its error is discarded.
+    pub fn push_try(&mut self, f: impl FnOnce() -> Result<(), E> + 'a) {
+        self.fs.push(Entry {
+            tag: None,
+            f: Box::new(f),
+        });
+    }
+
+    /// Runs and removes functions in reverse order of registration until one
+    /// fails, and returns its error. The functions registered before it are
+    /// kept until the DeferStack struct is dropped.
+    ///
+    /// Returns how many functions were run if none failed.
+    pub fn run_until_error(&mut self) -> Result<usize, E> {
+        let mut ran = 0;
+        while let Some(entry) = self.fs.pop() {
+            (entry.f)()?;
+            ran += 1;
+        }
+        Ok(ran)
+    }
+
+    /// Registers a function under the given tag, so it can be run early via
+    /// [`DeferStack::run_tag`].
+    pub fn push_tagged(&mut self, tag: &'static str, f: impl FnOnce() + 'a) {
+        self.fs.push(Entry {
+            tag: Some(tag),
+            f: Box::new(move || {
+                f();
+                Ok(())
+            }),
+        });
+    }
+
+    /// Runs and removes all functions registered under the given tag, in reverse
+    /// order of registration. The other functions are kept until the DeferStack
+    /// struct is dropped.
+    ///
+    /// Returns how many functions were run.
+    pub fn run_tag(&mut self, tag: &'static str) -> usize {
+        let mut ran = 0;
+        let mut i = self.fs.len();
+        while i > 0 {
+            i -= 1;
+            if self.fs[i].tag == Some(tag) {
+                let _ = (self.fs.remove(i).f)();
+                ran += 1;
+            }
+        }
+        ran
+    }
+
+    /// Returns the number of functions which have yet to be run.
+    pub fn len(&self) -> usize {
+        self.fs.len()
+    }
+
+    /// Returns true if there are no functions left to run.
+    pub fn is_empty(&self) -> bool {
+        self.fs.is_empty()
+    }
+
+    /// Returns the number of functions the DeferStack can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.fs.capacity()
+    }
+}
+
+impl Default for DeferStack<'_> {
+    fn default() -> Self {
+        DeferStack::new()
+    }
+}
+
+impl<'a, E> Extend<Box<dyn FnOnce() + 'a>> for DeferStack<'a, E> {
+    /// Registers all functions in iteration order, as if each was pushed in turn.
+    fn extend<I: IntoIterator<Item = Box<dyn FnOnce() + 'a>>>(&mut self, iter: I) {
+        for f in iter {
+            self.push(f)
+        }
+    }
+}
+
+impl<E> Drop for DeferStack<'_, E> {
+    /// Runs the remaining functions in reverse order of registration, discarding
+    /// the errors of fallible ones.
+    fn drop(&mut self) {
+        while let Some(entry) = self.fs.pop() {
+            let _ = (entry.f)();
+        }
+    }
+}
+
+/// The FixedDeferStack struct provides defer functionality for up to `N` functions
+/// of the same type, without allocating. The registered functions are run in
+/// reverse order of registration when the FixedDeferStack struct is dropped.
+pub struct FixedDeferStack<F: FnOnce(), const N: usize> {
+    fs: [Option<F>; N],
+    len: usize,
+}
+
+impl<F: FnOnce(), const N: usize> FixedDeferStack<F, N> {
+    /// Creates a new, empty FixedDeferStack instance.
+    pub fn new() -> FixedDeferStack<F, N> {
+        FixedDeferStack {
+            fs: std::array::from_fn(|_| None),
+            len: 0,
+        }
+    }
+
+    /// Registers a function to be run when the FixedDeferStack struct is dropped.
+    ///
+    /// Returns the function back as the error if all `N` slots are taken.
+    pub fn push(&mut self, f: F) -> Result<(), F> {
+        if self.len == N {
+            return Err(f);
+        }
+        self.fs[self.len] = Some(f);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Returns the number of functions which have yet to be run.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if there are no functions left to run.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<F: FnOnce(), const N: usize> Default for FixedDeferStack<F, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: FnOnce(), const N: usize> Drop for FixedDeferStack<F, N> {
+    /// Runs the registered functions in reverse order of registration.
+    fn drop(&mut self) {
+        while self.len > 0 {
+            self.len -= 1;
+            if let Some(f) = self.fs[self.len].take() {
+                f()
+            }
+        }
+    }
+}
+
+/// The functions deferred to a [`ScopeFrame`], in order of registration.
+struct Frame {
+    id: u64,
+    fs: Vec<Box<dyn FnOnce()>>,
+}
+
+thread_local! {
+    /// The live [`ScopeFrame`]s of this thread, outermost first.
+    static SCOPE_FRAMES: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+    static NEXT_FRAME_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The ScopeFrame struct marks a scope which functions can be deferred to from
+/// anywhere further in, see [`defer_at_depth!`]. Frames are tracked per thread:
+/// entering one pushes it onto the thread's frames and dropping it pops it
+/// again, running the functions deferred to it in reverse order of registration.
+///
+/// A ScopeFrame can't leave the thread it was entered on.
+pub struct ScopeFrame {
+    // Identifies the frame, as its position may be reused once an outer frame
+    // has popped it.
+    id: u64,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl ScopeFrame {
+    /// Enters a new frame, which becomes the innermost one of the current thread.
+    pub fn enter() -> ScopeFrame {
+        let id = NEXT_FRAME_ID.with(|next| next.replace(next.get() + 1));
+        SCOPE_FRAMES.with(|frames| frames.borrow_mut().push(Frame { id, fs: Vec::new() }));
+        ScopeFrame {
+            id,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Defers a function to the frame `depth` levels out from the innermost
+    /// one, so 0 is the innermost frame and 1 the one enclosing it.
+    ///
+    /// Panics if the current thread has less than `depth + 1` frames.
+    pub fn defer_at(depth: usize, f: impl FnOnce() + 'static) {
+        SCOPE_FRAMES.with(|frames| {
+            let mut frames = frames.borrow_mut();
+            let index = frames
+                .len()
+                .checked_sub(depth + 1)
+                .expect("defer_at_depth! used outside of a ScopeFrame at that depth");
+            frames[index].fs.push(Box::new(f));
+        })
+    }
+}
+
+impl Drop for ScopeFrame {
+    /// Pops the frame, along with any inner frames which are still live, and
+    /// runs their functions, innermost first. A frame which an outer one has
+    /// popped already is left alone.
+    fn drop(&mut self) {
+        let popped = SCOPE_FRAMES.with(|frames| {
+            let mut frames = frames.borrow_mut();
+            match frames.iter().position(|frame| frame.id == self.id) {
+                Some(index) => frames.split_off(index),
+                None => Vec::new(),
+            }
+        });
+        // The borrow has been released, so the functions may defer further out.
+        for frame in popped.into_iter().rev() {
+            for f in frame.fs.into_iter().rev() {
+                f()
+            }
+        }
+    }
+}
+
+/// Wraps an iterator so every item it yields comes with a cleanup, created from
+/// the item by `cleanup`.
+///
+/// An item's cleanup runs once the consumer moves past the item: at the start
+/// of the following call to `next`, before the next item is pulled from the
+/// inner iterator, or when the adapter is dropped. So cleanups run in the order
+/// of the items, and at most one is pending at a time.
+pub fn with_cleanup<I, C, G>(iter: I, cleanup: C) -> WithCleanup<I::IntoIter, C, G>
+where
+    I: IntoIterator,
+    C: FnMut(&I::Item) -> G,
+    G: FnOnce(),
+{
+    WithCleanup {
+        iter: iter.into_iter(),
+        cleanup,
+        pending: None,
+    }
+}
+
+/// The iterator returned by [`with_cleanup`].
+pub struct WithCleanup<I, C, G: FnOnce()> {
+    iter: I,
+    cleanup: C,
+    pending: Option<Defer<G>>,
+}
+
+impl<I, C, G> Iterator for WithCleanup<I, C, G>
+where
+    I: Iterator,
+    C: FnMut(&I::Item) -> G,
+    G: FnOnce(),
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        // Dropping the pending Defer runs the previous item's cleanup.
+        self.pending = None;
+        let item = self.iter.next()?;
+        self.pending = Some(Defer::new((self.cleanup)(&item)));
+        Some(item)
+    }
+}
+
+#[cfg(feature = "anyhow")]
+pub mod anyhow_defer {
+    use crate::Defer;
+    use anyhow::Context;
+    use std::sync::{Arc, Mutex};
+
+    /// Holds the error of a cleanup created via [`Defer::new_anyhow`], so it can
+    /// be inspected once the Defer has been dropped.
+    #[derive(Clone, Default)]
+    pub struct ErrorSlot {
+        error: Arc<Mutex<Option<anyhow::Error>>>,
+    }
+
+    impl ErrorSlot {
+        /// Takes the stored error, if the cleanup failed.
+        pub fn take_error(&self) -> Option<anyhow::Error> {
+            self.error.lock().unwrap().take()
+        }
+    }
+
+    impl Defer<fn()> {
+        /// Creates a Defer with a fallible function. If the function fails when
+        /// the Defer is dropped, its error is given `context` and stored in the
+        /// returned [`ErrorSlot`].
+        pub fn new_anyhow(
+            context: &'static str,
+            f: impl FnOnce() -> anyhow::Result<()>,
+        ) -> (Defer<impl FnOnce()>, ErrorSlot) {
+            let slot = ErrorSlot::default();
+            let error = slot.error.clone();
+            let defer = Defer::new(move || {
+                if let Err(e) = f().context(context) {
+                    *error.lock().unwrap() = Some(e);
+                }
+            });
+            (defer, slot)
+        }
+    }
+}
+
 #[cfg(feature = "async")]
-mod async_defer {
+pub mod async_defer {
+    use std::any::Any;
+    use std::fmt;
     use std::future::Future;
-    use std::sync::{Arc, Mutex};
-    use tokio::runtime::Runtime;
+    use std::marker::PhantomData;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex, PoisonError};
+    use std::time::Duration;
+    use tokio::runtime::{Handle, Runtime};
+    use tokio::task::JoinHandle;
+
+    /// How often a defer created from an external counter checks whether it reached 0.
+    const WATCH_INTERVAL: Duration = Duration::from_millis(1);
 
     /// The AsyncDefer struct provides defer functionality for asynchronous code.
     /// It takes a function which is run when the counter reaches 0.
+    ///
+    /// The deferred future is pinned on the heap before it is run, so it doesn't
+    /// need to be `Unpin`: futures returned by an `async fn` work just like
+    /// `async` blocks.
+    ///
+    /// Dropping an AsyncDefer whose counter reaches 0 at the same time has a
+    /// deterministic outcome: if the deferred function has started running, its
+    /// runtime is kept alive until the function has finished; otherwise the
+    /// function is discarded and never runs. It is never cut off halfway.
     pub struct AsyncDefer<F: Future<Output = ()> + Send + 'static> {
-        f: Option<F>,
-        rt: Runtime,
+        shared: Arc<Shared>,
+        handle: Handle,
+        // `None` once the runtime has been handed over to the shared state by
+        // `detach`, or while the defer is being dropped.
+        rt: Option<Runtime>,
+        _f: PhantomData<F>,
+    }
+
+    /// The state shared between an AsyncDefer and the tasks it spawns.
+    struct Shared {
+        f: Mutex<Option<Deferred>>,
         counter: Arc<Mutex<usize>>,
+        timeouts: AtomicUsize,
+        fire_on: Mutex<Option<Handle>>,
+        threshold: AtomicUsize,
+        detached: Mutex<Detached>,
+        outcome: Arc<Mutex<Option<CleanupOutcome>>>,
+        policy: CounterPolicy,
+        // Functions which have been executed, but haven't counted down yet.
+        in_flight: AtomicUsize,
+        #[cfg(feature = "serde")]
+        exec_calls: AtomicUsize,
+        #[cfg(feature = "test-util")]
+        fired: std::sync::atomic::AtomicBool,
+        #[cfg(feature = "test-util")]
+        fired_notify: tokio::sync::Notify,
+    }
+
+    /// The runtime handed over by `detach`, or by a defer dropped while its
+    /// deferred function is running. Kept under one lock with the `firing`
+    /// flag, so the runtime is shut down exactly when the function is done.
+    struct Detached {
+        rt: Option<Runtime>,
+        firing: bool,
+    }
+
+    impl Drop for Detached {
+        /// Shuts a runtime which is still held down without blocking, as the last
+        /// reference to the shared state may go away within asynchronous code.
+        fn drop(&mut self) {
+            if let Some(rt) = self.rt.take() {
+                rt.shutdown_background();
+            }
+        }
+    }
+
+    type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+    /// The deferred function. Its type is erased so the tasks spawned by an
+    /// AsyncDefer keep working after [`AsyncDefer::map_future`] has replaced it.
+    struct Deferred {
+        f: Box<dyn Any + Send>,
+        into_future: fn(Box<dyn Any + Send>) -> BoxFuture,
+    }
+
+    impl Deferred {
+        fn new<F: Future<Output = ()> + Send + 'static>(f: F) -> Deferred {
+            Deferred {
+                f: Box::new(f),
+                into_future: |f| Box::pin(*f.downcast::<F>().unwrap()),
+            }
+        }
+
+        /// Returns the function as the type it was created with.
+        fn into_inner<F: 'static>(self) -> F {
+            *self.f.downcast::<F>().unwrap()
+        }
+
+        fn into_future(self) -> BoxFuture {
+            (self.into_future)(self.f)
+        }
     }
 
     impl<F: Future<Output = ()> + Send + 'static> AsyncDefer<F> {
         /// Creates a new AsyncDefer instance with the provided function and counter.
         pub fn new(counter: usize, f: F) -> Arc<Mutex<Self>> {
-            let counter = Arc::new(Mutex::new(counter));
-            let defer = AsyncDefer {
-                f: Some(f),
-                rt: Runtime::new().unwrap(),
-                counter: counter.clone(),
-            };
+            Self::new_with_policy(counter, CounterPolicy::default(), f)
+        }
+
+        /// Creates a new AsyncDefer instance with the provided function and counter,
+        /// which handles functions executed past 0 according to `policy`.
+        pub fn new_with_policy(counter: usize, policy: CounterPolicy, f: F) -> Arc<Mutex<Self>> {
+            Arc::new(Mutex::new(Self::build(
+                Arc::new(Mutex::new(counter)),
+                policy,
+                f,
+            )))
+        }
+
+        /// Creates a new AsyncDefer instance which shares an externally owned counter.
+        ///
+        /// Other subsystems may count the defer down by calling [`tick`] on their clone
+        /// of the counter. The defer checks the counter periodically and runs the
+        /// deferred function once it reaches 0, so all decrements have to go through
+        /// [`tick`] or [`AsyncDefer::exec`]: a counter which is decremented past 0
+        /// panics, and one which is never brought to 0 never fires the defer.
+        ///
+        /// The check wakes the defer's runtime once per `WATCH_INTERVAL`, i.e.
+        /// every millisecond, for as long as the defer is neither fired nor
+        /// dropped, and [`tick`] only takes effect with the next check. Functions
+        /// executed via [`AsyncDefer::exec`] fire the defer right away instead.
+        pub fn from_counter_arc(counter: Arc<Mutex<usize>>, f: F) -> Arc<Mutex<Self>> {
+            let defer = Self::build(counter, CounterPolicy::default(), f);
+            let shared = defer.shared.clone();
+            defer.handle.spawn(async move {
+                loop {
+                    let due = {
+                        let counter = shared.counter.lock().unwrap();
+                        shared.reached_threshold(*counter).then(|| shared.take())
+                    };
+                    match due {
+                        Some(f) => break shared.run(f).await,
+                        None => tokio::time::sleep(WATCH_INTERVAL).await,
+                    }
+                }
+            });
             Arc::new(Mutex::new(defer))
         }
 
+        /// Creates a new AsyncDefer instance whose deferred function races against
+        /// `shutdown` once the counter reaches 0. If `shutdown` completes first the
+        /// deferred function is dropped, i.e. aborted at its current await point.
+        ///
+        /// Which of the two won is recorded, see [`AsyncDefer::outcome`].
+        pub fn with_shutdown(
+            counter: usize,
+            shutdown: impl Future + Send + 'static,
+            f: F,
+        ) -> Arc<Mutex<AsyncDefer<impl Future<Output = ()> + Send + 'static>>> {
+            let defer = Self::build(Arc::new(Mutex::new(counter)), CounterPolicy::default(), f);
+            let outcome = defer.shared.outcome.clone();
+            let defer = defer.map_future(|f| async move {
+                let won = tokio::select! {
+                    _ = f => CleanupOutcome::Completed,
+                    _ = shutdown => CleanupOutcome::ShutDown,
+                };
+                *outcome.lock().unwrap() = Some(won);
+            });
+            Arc::new(Mutex::new(defer))
+        }
+
+        /// Creates a new AsyncDefer instance which increments `completions` once
+        /// the deferred function has finished, e.g. for a test to wait on.
+        pub fn with_completion_counter(
+            counter: usize,
+            completions: Arc<AtomicUsize>,
+            f: F,
+        ) -> Arc<Mutex<AsyncDefer<impl Future<Output = ()> + Send + 'static>>> {
+            let defer = Self::build(Arc::new(Mutex::new(counter)), CounterPolicy::default(), f);
+            let defer = defer.map_future(|f| async move {
+                f.await;
+                completions.fetch_add(1, Ordering::SeqCst);
+            });
+            Arc::new(Mutex::new(defer))
+        }
+
+        fn build(counter: Arc<Mutex<usize>>, policy: CounterPolicy, f: F) -> Self {
+            let rt = Runtime::new().unwrap();
+            AsyncDefer {
+                shared: Arc::new(Shared {
+                    f: Mutex::new(Some(Deferred::new(f))),
+                    counter,
+                    timeouts: AtomicUsize::new(0),
+                    fire_on: Mutex::new(None),
+                    threshold: AtomicUsize::new(0),
+                    detached: Mutex::new(Detached {
+                        rt: None,
+                        firing: false,
+                    }),
+                    outcome: Arc::new(Mutex::new(None)),
+                    policy,
+                    in_flight: AtomicUsize::new(0),
+                    #[cfg(feature = "serde")]
+                    exec_calls: AtomicUsize::new(0),
+                    #[cfg(feature = "test-util")]
+                    fired: Default::default(),
+                    #[cfg(feature = "test-util")]
+                    fired_notify: tokio::sync::Notify::new(),
+                }),
+                handle: rt.handle().clone(),
+                rt: Some(rt),
+                _f: PhantomData,
+            }
+        }
+
+        /// Returns a handle to the defer's runtime, for spawning work alongside
+        /// the executed functions.
+        ///
+        /// The runtime is shut down when the AsyncDefer is dropped, or after the
+        /// deferred function has run if the defer was detached. From then on
+        /// tasks spawned via the handle are dropped without being run.
+        pub fn runtime_handle(&self) -> Handle {
+            self.handle.clone()
+        }
+
+        /// Hands the defer's runtime over to the defer's shared state, and returns
+        /// a handle for executing functions which doesn't need the AsyncDefer to
+        /// be kept around.
+        ///
+        /// Without this, dropping the AsyncDefer shuts its runtime down, losing
+        /// any functions still pending, the deferred one included. A detached
+        /// defer keeps its runtime alive until the deferred function has run
+        /// instead, or until the returned handle, all of its clones and the
+        /// AsyncDefer are dropped, at which point it is shut down without
+        /// blocking like on a regular drop.
+        pub fn detach(&mut self) -> ExecHandle {
+            if let Some(rt) = self.rt.take() {
+                self.shared.detached.lock().unwrap().rt = Some(rt);
+            }
+            ExecHandle {
+                shared: self.shared.clone(),
+                handle: self.handle.clone(),
+            }
+        }
+
+        /// Makes the deferred function run on the given runtime instead of the
+        /// defer's own one, which keeps running the executed functions.
+        pub fn fire_on(&mut self, handle: Handle) {
+            *self.shared.fire_on.lock().unwrap() = Some(handle);
+        }
+
+        /// Transforms the deferred function, e.g. to wrap it with logging or timing,
+        /// while keeping the counter and the rest of the defer's state. Functions
+        /// which were executed before, and are still running, count down towards
+        /// the transformed function as well.
+        ///
+        /// If the deferred function has already run, `g` is not called.
+        pub fn map_future<G: Future<Output = ()> + Send + 'static>(
+            mut self,
+            g: impl FnOnce(F) -> G,
+        ) -> AsyncDefer<G> {
+            {
+                // Held throughout, so the counter can't reach 0 between the two steps.
+                let mut f = self.shared.f.lock().unwrap();
+                if let Some(deferred) = f.take() {
+                    *f = Some(Deferred::new(g(deferred.into_inner())));
+                }
+            }
+            AsyncDefer {
+                shared: self.shared.clone(),
+                handle: self.handle.clone(),
+                rt: self.rt.take(),
+                _f: PhantomData,
+            }
+        }
+
+        /// Splits the defer into its counter and a handle for running the deferred
+        /// function, for wiring it into custom structures.
+        ///
+        /// From then on, whoever holds the parts decides when the deferred function
+        /// runs: decrementing the counter directly never fires it, only
+        /// [`CompletionHandle::fire`] does. Functions executed before the split
+        /// still fire it when they count the defer down to its threshold. Either
+        /// way it runs at most once. Like a detached defer, the runtime is kept
+        /// alive until the deferred function has run, or until the
+        /// [`CompletionHandle`] is dropped.
+        pub fn into_parts(mut self) -> (Arc<Mutex<usize>>, CompletionHandle) {
+            let ExecHandle { shared, handle } = self.detach();
+            (shared.counter.clone(), CompletionHandle { shared, handle })
+        }
+
+        /// Resets the counter to `counter` and stores `f` as the new deferred
+        /// function, so the defer can be reused once it has fired. A deferred
+        /// function which hasn't run yet is replaced without running.
+        ///
+        /// `f` has the defer's function type, so a defer to be rearmed is usually
+        /// created from an `async fn`, whose futures all share one type.
+        ///
+        /// The function and the counter are swapped under the counter's lock, and
+        /// a decrement which makes the function due takes it under that lock as
+        /// well. So a concurrent decrement either fires the old function or counts
+        /// down the new counter, never both. Functions which
+        /// are still running when the defer is rearmed count down the new
+        /// counter, so rearm once those of the previous round have finished.
+        /// The runtime of a detached defer is shut down once it has fired, and
+        /// the watcher of [`AsyncDefer::from_counter_arc`] stops, so only defers
+        /// created via [`AsyncDefer::new`] can be rearmed.
+        pub fn rearm(&self, counter: usize, f: F) {
+            // Locked in the same order as in `Shared::decrement`.
+            let mut remaining = self.shared.counter.lock().unwrap();
+            let mut deferred = self.shared.f.lock().unwrap();
+            *remaining = counter;
+            *self.shared.outcome.lock().unwrap() = None;
+            #[cfg(feature = "test-util")]
+            self.shared.fired.store(false, Ordering::SeqCst);
+            *deferred = Some(Deferred::new(f));
+        }
+
+        /// Makes the deferred function run as soon as the counter has been counted
+        /// down to `threshold`, instead of 0, e.g. to start cleaning up while the
+        /// last few functions are still being executed.
+        ///
+        /// A counter which is already at or below the threshold fires the defer
+        /// with its next decrement.
+        pub fn fire_at(&mut self, threshold: usize) {
+            self.shared.threshold.store(threshold, Ordering::SeqCst);
+        }
+
+        /// Returns the current value of the counter.
+        pub fn remaining(&self) -> usize {
+            *self.shared.counter.lock().unwrap()
+        }
+
         /// Executes a function and decrements the counter.
         /// When the counter reaches 0, the deferred function is run.
-        pub fn exec(&mut self, action: impl FnOnce() + Send + 'static) {
-            let counter = self.counter.lock().unwrap().clone();
-            self.rt.spawn(async move {
-                action();
-                let mut counter = counter.lock().unwrap();
-                *counter -= 1;
+        ///
+        /// What happens to functions executed past 0 depends on the defer's
+        /// [`CounterPolicy`].
+        pub fn exec(
+            &mut self,
+            action: impl FnOnce() + Send + 'static,
+        ) -> Result<(), CounterExhausted> {
+            spawn_exec(&self.handle, &self.shared, action)
+        }
+
+        /// Executes a future and decrements the counter once it has completed.
+        /// When the counter reaches 0, the deferred function is run.
+        pub fn exec_async(
+            &mut self,
+            action: impl Future<Output = ()> + Send + 'static,
+        ) -> Result<(), CounterExhausted> {
+            self.shared.claim()?;
+            let shared = self.shared.clone();
+            self.handle.spawn(async move {
+                action.await;
+                shared.count_down().await;
+            });
+            Ok(())
+        }
+
+        /// Executes a function on a blocking thread and decrements the counter
+        /// once it has finished. When the counter reaches 0, the deferred function
+        /// is run.
+        ///
+        /// Use this instead of [`AsyncDefer::exec`] for CPU-heavy or otherwise
+        /// blocking functions, so they don't hold up the runtime's workers.
+        pub fn exec_blocking(
+            &mut self,
+            action: impl FnOnce() + Send + 'static,
+        ) -> Result<(), CounterExhausted> {
+            self.shared.claim()?;
+            let shared = self.shared.clone();
+            self.handle.spawn(async move {
+                // The counter is decremented even if the function panicked.
+                let _ = tokio::task::spawn_blocking(action).await;
+                shared.count_down().await;
             });
+            Ok(())
+        }
+
+        /// Executes a function and decrements the counter once it has finished,
+        /// or once `dur` has elapsed, whichever comes first.
+        /// When the counter reaches 0, the deferred function is run.
+        ///
+        /// The function runs on a blocking thread and is not aborted when it
+        /// times out: it keeps running in the background, but no longer holds
+        /// up the defer. Timed out functions can be counted via
+        /// [`AsyncDefer::timeouts`].
+        pub fn exec_timeout(
+            &mut self,
+            dur: Duration,
+            action: impl FnOnce() + Send + 'static,
+        ) -> Result<(), CounterExhausted> {
+            self.shared.claim()?;
+            let shared = self.shared.clone();
+            self.handle.spawn(async move {
+                let action = tokio::task::spawn_blocking(action);
+                if tokio::time::timeout(dur, action).await.is_err() {
+                    shared.timeouts.fetch_add(1, Ordering::SeqCst);
+                }
+                shared.count_down().await;
+            });
+            Ok(())
+        }
+
+        /// Increments the counter and returns a token which decrements it again
+        /// when dropped, running the deferred function if that brings it to 0.
+        ///
+        /// Tokens replace manual counting: create the defer with a counter of 0
+        /// and hand a token to every piece of work the deferred function has to
+        /// wait for.
+        pub fn exec_token(&self) -> ExecToken {
+            *self.shared.counter.lock().unwrap() += 1;
+            ExecToken {
+                shared: self.shared.clone(),
+                handle: self.runtime_handle(),
+            }
+        }
+
+        /// Returns how many functions executed via [`AsyncDefer::exec_timeout`]
+        /// have timed out.
+        pub fn timeouts(&self) -> usize {
+            self.shared.timeouts.load(Ordering::SeqCst)
+        }
+
+        /// Blocks the current thread until the deferred function has run, so a
+        /// test can check its effects without sleeping.
+        ///
+        /// Returns right away if it has run already. Must not be called from
+        /// within an asynchronous context, and never returns if the counter
+        /// doesn't reach its threshold.
+        #[cfg(feature = "test-util")]
+        pub fn block_until_fired(&self) {
+            let shared = &self.shared;
+            self.handle.block_on(async {
+                loop {
+                    // Registered before checking, so a notification in between isn't lost.
+                    let notified = shared.fired_notify.notified();
+                    if shared.fired.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    notified.await;
+                }
+            })
+        }
+
+        /// Captures the defer's progress, e.g. to log it as JSON.
+        #[cfg(feature = "serde")]
+        pub fn snapshot_state(&self) -> StateSnapshot {
+            StateSnapshot {
+                remaining: self.remaining(),
+                fired: self.shared.f.lock().unwrap().is_none(),
+                exec_calls: self.shared.exec_calls.load(Ordering::SeqCst),
+            }
+        }
+
+        /// Returns how the deferred function ended, or `None` if it hasn't yet.
+        pub fn outcome(&self) -> Option<CleanupOutcome> {
+            *self.shared.outcome.lock().unwrap()
+        }
+    }
+
+    /// A serializable snapshot of an AsyncDefer's progress, created via
+    /// [`AsyncDefer::snapshot_state`].
+    #[cfg(feature = "serde")]
+    #[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+    pub struct StateSnapshot {
+        /// The current value of the counter.
+        pub remaining: usize,
+        /// Whether the deferred function has started running.
+        pub fired: bool,
+        /// How many functions have been executed, not counting those rejected
+        /// under [`CounterPolicy::Error`].
+        pub exec_calls: usize,
+    }
+
+    /// How an AsyncDefer handles functions executed once its counter is used up.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub enum CounterPolicy {
+        /// Panic in the call executing the function.
+        Panic,
+        /// Run the function, but keep the counter at 0. The deferred function
+        /// still runs only once.
+        #[default]
+        Saturate,
+        /// Don't run the function, and return [`CounterExhausted`] instead.
+        Error,
+    }
+
+    /// The error returned for functions executed once the counter is used up,
+    /// under [`CounterPolicy::Error`].
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct CounterExhausted;
+
+    impl fmt::Display for CounterExhausted {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("executed more functions than the AsyncDefer's counter allows")
+        }
+    }
+
+    impl std::error::Error for CounterExhausted {}
+
+    /// How the deferred function of an AsyncDefer ended.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum CleanupOutcome {
+        /// The deferred function ran to completion.
+        Completed,
+        /// The shutdown signal passed to [`AsyncDefer::with_shutdown`] completed
+        /// first, and the deferred function was aborted.
+        ShutDown,
+    }
+
+    /// A handle returned by [`AsyncDefer::detach`], for executing functions on a
+    /// detached AsyncDefer.
+    #[derive(Clone)]
+    pub struct ExecHandle {
+        shared: Arc<Shared>,
+        handle: Handle,
+    }
+
+    impl ExecHandle {
+        /// Executes a function and decrements the counter.
+        /// When the counter reaches 0, the deferred function is run.
+        pub fn exec(&self, action: impl FnOnce() + Send + 'static) -> Result<(), CounterExhausted> {
+            spawn_exec(&self.handle, &self.shared, action)
+        }
+    }
+
+    /// Something which can be executed before an AsyncDefer: either a function,
+    /// which goes to [`AsyncDefer::exec`], or a future, which goes to
+    /// [`AsyncDefer::exec_async`]. Used by `exec_before_defer!` to accept both.
+    ///
+    /// `Kind` is [`FnAction`] or [`FutureAction`]. It only keeps the two
+    /// implementations apart and is always inferred.
+    pub trait Executable<Kind> {
+        /// Executes `self` via the matching method of `defer`.
+        fn exec_on<F: Future<Output = ()> + Send + 'static>(
+            self,
+            defer: &mut AsyncDefer<F>,
+        ) -> Result<(), CounterExhausted>;
+    }
+
+    /// Marks the [`Executable`] implementation for functions.
+    pub enum FnAction {}
+
+    /// Marks the [`Executable`] implementation for futures.
+    pub enum FutureAction {}
+
+    impl<A: FnOnce() + Send + 'static> Executable<FnAction> for A {
+        fn exec_on<F: Future<Output = ()> + Send + 'static>(
+            self,
+            defer: &mut AsyncDefer<F>,
+        ) -> Result<(), CounterExhausted> {
+            defer.exec(self)
+        }
+    }
+
+    impl<A: Future<Output = ()> + Send + 'static> Executable<FutureAction> for A {
+        fn exec_on<F: Future<Output = ()> + Send + 'static>(
+            self,
+            defer: &mut AsyncDefer<F>,
+        ) -> Result<(), CounterExhausted> {
+            defer.exec_async(self)
+        }
+    }
+
+    /// A handle returned by [`AsyncDefer::into_parts`], for running the deferred
+    /// function of a decomposed AsyncDefer.
+    pub struct CompletionHandle {
+        shared: Arc<Shared>,
+        handle: Handle,
+    }
+
+    impl CompletionHandle {
+        /// Runs the deferred function on the defer's runtime, unless it has
+        /// already been run. The returned handle resolves once it has finished.
+        pub fn fire(&self) -> JoinHandle<()> {
+            let shared = self.shared.clone();
+            self.handle.spawn(async move { shared.fire().await })
+        }
+    }
+
+    fn spawn_exec(
+        handle: &Handle,
+        shared: &Arc<Shared>,
+        action: impl FnOnce() + Send + 'static,
+    ) -> Result<(), CounterExhausted> {
+        shared.claim()?;
+        let shared = shared.clone();
+        handle.spawn(async move {
+            action();
+            shared.count_down().await;
+        });
+        Ok(())
+    }
+
+    /// A token handed out by [`AsyncDefer::exec_token`], which counts down its
+    /// AsyncDefer when dropped.
+    pub struct ExecToken {
+        shared: Arc<Shared>,
+        handle: Handle,
+    }
+
+    impl Drop for ExecToken {
+        /// Decrements the counter, running the deferred function when it reaches 0.
+        fn drop(&mut self) {
+            let f = self.shared.decrement(false);
+            if f.is_some() {
+                let shared = self.shared.clone();
+                self.handle.spawn(async move { shared.run(f).await });
+            }
+        }
+    }
+
+    impl Shared {
+        /// Decrements the counter, running the deferred function when it reaches 0.
+        async fn count_down(&self) {
+            let f = self.decrement(true);
+            if f.is_some() {
+                self.run(f).await;
+            }
+        }
+
+        /// Reserves one count for a function which is about to be executed,
+        /// applying the policy if all counts have been used up already.
+        fn claim(&self) -> Result<(), CounterExhausted> {
+            let exhausted = {
+                let counter = self.counter.lock().unwrap();
+                let exhausted = self.in_flight.load(Ordering::SeqCst) >= *counter;
+                if !exhausted || self.policy == CounterPolicy::Saturate {
+                    self.in_flight.fetch_add(1, Ordering::SeqCst);
+                    #[cfg(feature = "serde")]
+                    self.exec_calls.fetch_add(1, Ordering::SeqCst);
+                }
+                exhausted
+            };
+            match (exhausted, self.policy) {
+                (true, CounterPolicy::Panic) => panic!("{}", CounterExhausted),
+                (true, CounterPolicy::Error) => Err(CounterExhausted),
+                _ => Ok(()),
+            }
+        }
+
+        /// Decrements the counter, saturating at 0 unless the policy is to panic.
+        /// `claimed` releases a count reserved via `claim`.
+        ///
+        /// Returns the deferred function if it is now due, taken while the counter
+        /// is still locked, so a concurrent `rearm` can't swap it in between.
+        fn decrement(&self, claimed: bool) -> Option<Deferred> {
+            let mut counter = self.counter.lock().unwrap();
+            if claimed {
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            }
+            if *counter == 0 && self.policy == CounterPolicy::Panic {
+                drop(counter);
+                panic!("{}", CounterExhausted);
+            }
+            *counter = counter.saturating_sub(1);
+            if self.reached_threshold(*counter) {
+                self.take()
+            } else {
+                None
+            }
+        }
+
+        /// Returns true if the deferred function is due at the given count.
+        fn reached_threshold(&self, remaining: usize) -> bool {
+            remaining <= self.threshold.load(Ordering::SeqCst)
+        }
+
+        /// Runs the deferred function, unless it has already been taken.
+        async fn fire(&self) {
+            self.run(self.take()).await
+        }
+
+        /// Takes the deferred function, marking it as running.
+        fn take(&self) -> Option<Deferred> {
+            let mut f = self.f.lock().unwrap();
+            // Marked while the function is still locked, see `AsyncDefer::drop`.
+            if f.is_some() {
+                self.detached.lock().unwrap().firing = true;
+            }
+            f.take()
+        }
+
+        /// Runs a deferred function taken via `take`.
+        async fn run(&self, f: Option<Deferred>) {
+            // Finishes up even if the deferred function panics.
+            let _firing = Firing {
+                shared: self,
+                took: f.is_some(),
+            };
+            if let Some(f) = f {
+                let f = f.into_future();
+                let fire_on = self.fire_on.lock().unwrap().clone();
+                let finished = match fire_on {
+                    // A runtime which has been shut down drops the function unrun.
+                    Some(handle) => handle.spawn(f).await.is_ok(),
+                    None => {
+                        f.await;
+                        true
+                    }
+                };
+                if finished {
+                    let mut outcome = self.outcome.lock().unwrap();
+                    outcome.get_or_insert(CleanupOutcome::Completed);
+                }
+            }
+        }
+    }
+
+    /// Held by `Shared::fire` while it runs the deferred function. Dropping it
+    /// marks the function as fired, if this call took it, and shuts the
+    /// detached runtime down once no call is running the function anymore.
+    struct Firing<'a> {
+        shared: &'a Shared,
+        took: bool,
+    }
+
+    impl Drop for Firing<'_> {
+        fn drop(&mut self) {
+            let rt = {
+                let detached = &self.shared.detached;
+                let mut detached = detached.lock().unwrap_or_else(PoisonError::into_inner);
+                if self.took {
+                    detached.firing = false;
+                    #[cfg(feature = "test-util")]
+                    {
+                        self.shared.fired.store(true, Ordering::SeqCst);
+                        self.shared.fired_notify.notify_waiters();
+                    }
+                }
+                // Another call may still be running the function on the runtime.
+                if detached.firing {
+                    None
+                } else {
+                    detached.rt.take()
+                }
+            };
+            if let Some(rt) = rt {
+                rt.shutdown_background();
+            }
         }
     }
+
+    impl<F: Future<Output = ()> + Send + 'static> Drop for AsyncDefer<F> {
+        /// Shuts the runtime down without blocking, so the defer can be dropped
+        /// from within asynchronous code. A deferred function which is already
+        /// running is let finish first, one which isn't is discarded.
+        fn drop(&mut self) {
+            if let Some(rt) = self.rt.take() {
+                // Taken under the same lock `fire` takes it under, so either the
+                // function is discarded here or `fire` has marked it as running.
+                let discarded = self.shared.f.lock().unwrap().take();
+                let mut detached = self.shared.detached.lock().unwrap();
+                if detached.firing {
+                    detached.rt = Some(rt);
+                } else {
+                    drop(detached);
+                    rt.shutdown_background();
+                }
+                drop(discarded);
+            }
+        }
+    }
+
+    /// Decrements a counter shared with an AsyncDefer and returns the remaining count.
+    ///
+    /// Panics if the counter is already 0.
+    pub fn tick(counter: &Mutex<usize>) -> usize {
+        let mut counter = counter.lock().unwrap();
+        if *counter == 0 {
+            // Released first, so the counter isn't poisoned for everyone else.
+            drop(counter);
+            panic!("counter decremented past 0");
+        }
+        *counter -= 1;
+        *counter
+    }
 }
 
 /// A macro for creating a Defer instance.
 /// This macro takes a block of code to be deferred.
+///
+/// Written as `defer!(name = { ... })`, it binds the Defer to `name` instead,
+/// so it can later be cancelled via [`undefer!`].
 #[macro_export]
 macro_rules! defer {
+    ($name:ident = { $($t:tt)* }) => {
+        #[allow(unused_mut, unused_variables)]
+        let mut $name = $crate::Defer::new(|| { $($t)* });
+    };
     ($($t:tt)*) => {
         $crate::Defer::new(|| { $($t)* })
     };
 }
 
+/// A macro for cancelling a Defer bound via `defer!(name = { ... })`.
+/// This macro takes the name of the Defer to cancel.
+#[macro_export]
+macro_rules! undefer {
+    ($name:ident) => {
+        $name.cancel()
+    };
+}
+
+/// A macro for deferring a single call, e.g. `defer_call!(file.close())`.
+/// This macro takes the call expression and moves everything it uses, the
+/// receiver included, into the returned Defer. The call's return value is
+/// discarded.
+#[macro_export]
+macro_rules! defer_call {
+    ($call:expr) => {
+        $crate::Defer::new(move || {
+            let _ = $call;
+        })
+    };
+}
+
+/// A macro for deferring a block of code to an enclosing [`ScopeFrame`].
+/// This macro takes the depth of the frame, 0 being the innermost, and a block
+/// of code which is run when that frame is dropped.
+#[macro_export]
+macro_rules! defer_at_depth {
+    ($depth:expr, { $($t:tt)* }) => {
+        $crate::ScopeFrame::defer_at($depth, move || { $($t)* })
+    };
+}
+
+/// A macro for declaring a struct whose guard fields run before anything else
+/// about the struct is dropped.
+///
+/// Fields marked with `#[rdefer_guard]` must be a [`Defer`] or a [`DynDefer`].
+/// The macro implements `Drop` for the struct, running the marked fields in
+/// declaration order while all other fields are still alive. Without it, fields
+/// are dropped in declaration order, so a guard's position in the struct would
+/// decide when it runs.
+///
+/// The struct must have named fields and no generic parameters, and must not
+/// implement `Drop` itself.
+///
+/// ```
+/// rdefer::guarded_struct! {
+///     pub struct Connection {
+///         socket: std::net::TcpStream,
+///         /// Says goodbye while the socket is still open.
+///         #[rdefer_guard]
+///         goodbye: rdefer::DynDefer,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! guarded_struct {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident { $($body:tt)* }
+    ) => {
+        $crate::guarded_struct!(@munch [$(#[$meta])* $vis struct] $name [] [] $($body)*);
+    };
+    (@munch $head:tt $name:ident $fields:tt $guards:tt
+        $(#[$($attr:tt)*])* $fvis:vis $field:ident : $ty:ty $(, $($rest:tt)*)?
+    ) => {
+        $crate::guarded_struct!(@attrs $head $name $fields $guards [$fvis $field: $ty] [] []
+            $(#[$($attr)*])* ; $($($rest)*)?);
+    };
+    // Splits `#[rdefer_guard]` off the field's attributes, wherever it is among them.
+    (@attrs $head:tt $name:ident $fields:tt $guards:tt $decl:tt [$($kept:tt)*] $guard:tt
+        #[rdefer_guard] $($more:tt)*
+    ) => {
+        $crate::guarded_struct!(@attrs $head $name $fields $guards $decl [$($kept)*] [guard]
+            $($more)*);
+    };
+    (@attrs $head:tt $name:ident $fields:tt $guards:tt $decl:tt [$($kept:tt)*] $guard:tt
+        #[$($attr:tt)*] $($more:tt)*
+    ) => {
+        $crate::guarded_struct!(@attrs $head $name $fields $guards $decl
+            [$($kept)* #[$($attr)*]] $guard $($more)*);
+    };
+    (@attrs $head:tt $name:ident [$($fields:tt)*] [$($guards:ident)*]
+        [$fvis:vis $field:ident : $ty:ty] [$($kept:tt)*] [] ; $($rest:tt)*
+    ) => {
+        $crate::guarded_struct!(@munch $head $name
+            [$($fields)* $($kept)* $fvis $field: $ty,] [$($guards)*] $($rest)*);
+    };
+    (@attrs $head:tt $name:ident [$($fields:tt)*] [$($guards:ident)*]
+        [$fvis:vis $field:ident : $ty:ty] [$($kept:tt)*] [guard] ; $($rest:tt)*
+    ) => {
+        $crate::guarded_struct!(@munch $head $name
+            [$($fields)* $($kept)* $fvis $field: $ty,] [$($guards)* $field] $($rest)*);
+    };
+    (@munch [$($head:tt)*] $name:ident [$($fields:tt)*] [$($guards:ident)*]) => {
+        $($head)* $name { $($fields)* }
+
+        impl ::core::ops::Drop for $name {
+            fn drop(&mut self) {
+                $(self.$guards.now();)*
+            }
+        }
+    };
+}
+
 #[cfg(feature = "async")]
 /// A macro for creating an AsyncDefer instance.
 /// This macro takes a count and a block of async code to be deferred.
+///
+/// The deferred future runs on the defer's own runtime, so it has to be
+/// `Send + 'static`.
 #[macro_export]
 macro_rules! async_defer {
     ($count:expr, $f:expr) => {
@@ -87,10 +2072,17 @@ macro_rules! async_defer {
 
 #[cfg(feature = "async")]
 /// A macro for executing code before the async defer.
-/// This macro takes an AsyncDefer instance and a block of code to execute.
+/// This macro takes an AsyncDefer instance and either a closure or an async
+/// block to execute.
+///
+/// Closures are passed to [`AsyncDefer::exec`](crate::async_defer::AsyncDefer::exec)
+/// and async blocks, or any other future, to
+/// [`AsyncDefer::exec_async`](crate::async_defer::AsyncDefer::exec_async), whose
+/// result the macro returns. The choice is made at compile time, via
+/// [`Executable`](crate::async_defer::Executable).
 #[macro_export]
 macro_rules! exec_before_defer {
     ($defer:expr, $action:expr) => {
-        $defer.lock().unwrap().exec($action)
+        $crate::async_defer::Executable::exec_on($action, &mut *$defer.lock().unwrap())
     };
 }
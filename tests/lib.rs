@@ -1,5 +1,10 @@
-use rdefer::Defer;
+use rdefer::{
+    Defer, DeferStack, DynDefer, FixedDeferStack, FlushDefer, LazyDefer, OnceDefer, ThreadDefer,
+    ThreadRouter,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[test]
 fn test_defer() {
@@ -10,6 +15,842 @@ fn test_defer() {
     assert_eq!(value, 1);
 }
 
+#[test]
+fn test_defers_run_in_reverse_declaration_order() {
+    use rdefer::defer;
+
+    let log = std::cell::RefCell::new(Vec::new());
+    {
+        let _a = defer! { log.borrow_mut().push("a") };
+        let _b = defer! { log.borrow_mut().push("b") };
+        defer!(c = { log.borrow_mut().push("c") });
+        let _d = Defer::new(|| log.borrow_mut().push("d"));
+        let _e = defer! { log.borrow_mut().push("e") };
+    }
+    assert_eq!(*log.borrow(), ["e", "d", "c", "b", "a"]);
+}
+
+#[test]
+fn test_named_defer_and_undefer() {
+    use rdefer::{defer, undefer};
+
+    let rolled_back = std::cell::Cell::new(false);
+    {
+        defer!(
+            rollback = {
+                rolled_back.set(true);
+            }
+        );
+        // Everything went fine, nothing to roll back.
+        undefer!(rollback);
+    }
+    assert!(!rolled_back.get());
+
+    {
+        defer!(
+            rollback = {
+                rolled_back.set(true);
+            }
+        );
+    }
+    assert!(rolled_back.get());
+}
+
+#[test]
+fn test_defer_call() {
+    use rdefer::defer_call;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Connection {
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Connection {
+        fn close(&self, reason: &str) -> bool {
+            self.log.borrow_mut().push(format!("closed: {}", reason));
+            true
+        }
+    }
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    {
+        let connection = Connection {
+            log: Rc::clone(&log),
+        };
+        let reason = String::from("done");
+        let _d = defer_call!(connection.close(&reason));
+        assert!(log.borrow().is_empty());
+    }
+    assert_eq!(*log.borrow(), ["closed: done"]);
+}
+
+#[test]
+fn test_defer_cancel_and_now() {
+    let runs = std::cell::Cell::new(0);
+    {
+        let mut d = Defer::new(|| runs.set(runs.get() + 1));
+        d.cancel();
+        assert!(!d.is_armed());
+    }
+    assert_eq!(runs.get(), 0);
+    {
+        let mut d = Defer::new(|| runs.set(runs.get() + 1));
+        assert!(d.is_armed());
+        d.now();
+        assert_eq!(runs.get(), 1);
+        assert!(!d.is_armed());
+    }
+    assert_eq!(runs.get(), 1);
+}
+
+#[cfg(feature = "backtrace")]
+#[test]
+fn test_defer_creation_backtrace() {
+    use std::backtrace::BacktraceStatus;
+
+    let d = Defer::new_traced(|| {});
+    let backtrace = d.creation_backtrace().unwrap();
+    assert_eq!(backtrace.status(), BacktraceStatus::Captured);
+
+    assert!(Defer::new(|| {}).creation_backtrace().is_none());
+}
+
+#[test]
+fn test_drop_value() {
+    struct Resource<'a>(&'a std::cell::RefCell<Vec<&'static str>>);
+
+    impl Drop for Resource<'_> {
+        fn drop(&mut self) {
+            self.0.borrow_mut().push("resource");
+        }
+    }
+
+    let log = std::cell::RefCell::new(Vec::new());
+    {
+        let _after = Defer::new(|| log.borrow_mut().push("after"));
+        let _d = Defer::drop_value(Resource(&log));
+        let _before = Defer::new(|| log.borrow_mut().push("before"));
+        assert!(log.borrow().is_empty());
+    }
+    assert_eq!(*log.borrow(), ["before", "resource", "after"]);
+
+    log.borrow_mut().clear();
+    {
+        let mut d = Defer::drop_value(Resource(&log));
+        d.now();
+        log.borrow_mut().push("end of scope");
+    }
+    assert_eq!(*log.borrow(), ["resource", "end of scope"]);
+}
+
+#[test]
+fn test_unlock() {
+    use std::sync::{Mutex, RwLock};
+
+    let lock = Mutex::new(0);
+    std::thread::scope(|scope| {
+        let mut d = Defer::unlock(lock.lock().unwrap());
+        assert!(scope.spawn(|| lock.try_lock().is_err()).join().unwrap());
+
+        d.now();
+        assert!(scope.spawn(|| lock.try_lock().is_ok()).join().unwrap());
+    });
+
+    let lock = RwLock::new(0);
+    {
+        let _d = Defer::unlock(lock.write().unwrap());
+        assert!(lock.try_read().is_err());
+    }
+    assert!(lock.try_read().is_ok());
+}
+
+#[test]
+fn test_compose_all() {
+    let log = std::cell::RefCell::new(Vec::new());
+    {
+        let _d = Defer::compose_all(vec![
+            Box::new(|| log.borrow_mut().push(1)),
+            Box::new(|| log.borrow_mut().push(2)),
+            Box::new(|| log.borrow_mut().push(3)),
+        ]);
+        assert!(log.borrow().is_empty());
+    }
+    assert_eq!(*log.borrow(), [3, 2, 1]);
+}
+
+#[test]
+fn test_defer_add() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let entry = |i| {
+        let log = Rc::clone(&log);
+        Defer::new(move || log.borrow_mut().push(i))
+    };
+    {
+        let _g = entry(1) + entry(2) + entry(3);
+        assert!(log.borrow().is_empty());
+    }
+    assert_eq!(*log.borrow(), [3, 2, 1]);
+}
+
+#[test]
+fn test_new_mut() {
+    let mut d = Defer::new_mut(vec![1], |log| log.push(3));
+    d.state_mut().push(2);
+    assert_eq!(d.take_state(), [1, 2, 3]);
+
+    let flushed = std::cell::Cell::new(0);
+    {
+        let _d = Defer::new_mut(0, |count| {
+            *count += 1;
+            flushed.set(*count);
+        });
+    }
+    assert_eq!(flushed.get(), 1);
+}
+
+#[test]
+fn test_flush_writer() {
+    use std::io::{BufWriter, Write};
+
+    let mut sink = Vec::new();
+    let report = {
+        let (mut writer, report) = Defer::flush_writer(BufWriter::new(&mut sink));
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"world").unwrap();
+        assert_eq!(report.bytes_written(), None);
+        report
+    };
+    assert_eq!(report.bytes_written(), Some(11));
+    assert!(report.take_flush_error().is_none());
+    assert_eq!(sink, b"hello world");
+}
+
+#[test]
+fn test_dyn_defer_field() {
+    struct Connection {
+        _cleanup: DynDefer,
+    }
+
+    let closed = Arc::new(AtomicBool::new(false));
+    let connection = Connection {
+        _cleanup: DynDefer::new({
+            let closed = Arc::clone(&closed);
+            move || closed.store(true, Ordering::SeqCst)
+        }),
+    };
+    assert!(!closed.load(Ordering::SeqCst));
+
+    drop(connection);
+    assert!(closed.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_thread_defer_routes_to_origin() {
+    use std::sync::Mutex;
+    use std::thread;
+
+    let router = ThreadRouter::install();
+    let origin = thread::current().id();
+    let ran_on = Arc::new(Mutex::new(None));
+    let d = ThreadDefer::new({
+        let ran_on = Arc::clone(&ran_on);
+        move || *ran_on.lock().unwrap() = Some(thread::current().id())
+    });
+    assert_eq!(d.origin(), origin);
+
+    thread::spawn(move || drop(d)).join().unwrap();
+    assert_eq!(*ran_on.lock().unwrap(), None);
+
+    assert_eq!(router.run_pending(), 1);
+    assert_eq!(*ran_on.lock().unwrap(), Some(origin));
+}
+
+#[test]
+fn test_thread_defer_without_router_panics() {
+    use std::thread;
+
+    let d = ThreadDefer::new(|| {});
+    assert!(thread::spawn(move || drop(d)).join().is_err());
+}
+
+#[test]
+fn test_guarded_struct() {
+    use std::sync::Mutex;
+
+    type Log = Arc<Mutex<Vec<&'static str>>>;
+
+    struct Socket(Log);
+
+    impl Drop for Socket {
+        fn drop(&mut self) {
+            self.0.lock().unwrap().push("socket closed");
+        }
+    }
+
+    rdefer::guarded_struct! {
+        /// A connection which says goodbye while its socket is still open.
+        struct Connection {
+            socket: Socket,
+            #[rdefer_guard]
+            goodbye: DynDefer,
+            name: &'static str,
+        }
+    }
+
+    let log: Log = Arc::new(Mutex::new(Vec::new()));
+    let connection = Connection {
+        socket: Socket(Arc::clone(&log)),
+        goodbye: DynDefer::new({
+            let log = Arc::clone(&log);
+            move || log.lock().unwrap().push("goodbye sent")
+        }),
+        name: "db",
+    };
+    assert_eq!(connection.name, "db");
+
+    drop(connection);
+    assert_eq!(*log.lock().unwrap(), ["goodbye sent", "socket closed"]);
+}
+
+#[test]
+fn test_guarded_struct_documented_guard() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Recorder(Rc<RefCell<Vec<&'static str>>>, &'static str);
+
+    impl Drop for Recorder {
+        fn drop(&mut self) {
+            self.0.borrow_mut().push(self.1);
+        }
+    }
+
+    rdefer::guarded_struct! {
+        struct Session {
+            /// Dropped after the guards.
+            state: Recorder,
+            /// Runs first, despite its doc comment.
+            #[rdefer_guard]
+            #[allow(dead_code)]
+            pub flush: Defer<Box<dyn FnOnce()>>,
+        }
+    }
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let session = Session {
+        state: Recorder(Rc::clone(&log), "state dropped"),
+        flush: Defer::new(Box::new({
+            let log = Rc::clone(&log);
+            move || log.borrow_mut().push("flushed")
+        })),
+    };
+    drop(session);
+    assert_eq!(*log.borrow(), ["flushed", "state dropped"]);
+}
+
+#[test]
+fn test_on_outcome_ok() {
+    let outcome = std::cell::Cell::new("");
+    let success = Arc::new(AtomicBool::new(false));
+    {
+        let _d = Defer::on_outcome(success.clone(), || outcome.set("ok"), || outcome.set("err"));
+        success.store(true, Ordering::SeqCst);
+    }
+    assert_eq!(outcome.get(), "ok");
+}
+
+#[test]
+fn test_on_outcome_err() {
+    let outcome = std::cell::Cell::new("");
+    let success = Arc::new(AtomicBool::new(false));
+    let run = || -> Result<(), ()> {
+        let _d = Defer::on_outcome(success.clone(), || outcome.set("ok"), || outcome.set("err"));
+        Err(())?;
+        success.store(true, Ordering::SeqCst);
+        Ok(())
+    };
+    assert!(run().is_err());
+    assert_eq!(outcome.get(), "err");
+}
+
+#[test]
+fn test_tap() {
+    let setups = std::cell::Cell::new(0);
+    let teardowns = std::cell::Cell::new(0);
+    {
+        let _g = Defer::tap(
+            || setups.set(setups.get() + 1),
+            || teardowns.set(teardowns.get() + 1),
+        );
+        assert_eq!((setups.get(), teardowns.get()), (1, 0));
+    }
+    assert_eq!((setups.get(), teardowns.get()), (1, 1));
+}
+
+#[test]
+fn test_from_result_fn_ok() {
+    let released = std::cell::Cell::new(None);
+    {
+        let _d = Defer::from_result_fn(
+            || Ok::<_, &str>(7),
+            |handle| released.set(Some(Ok(handle))),
+            |error| released.set(Some(Err(error))),
+        );
+        assert_eq!(released.get(), None);
+    }
+    assert_eq!(released.get(), Some(Ok(7)));
+}
+
+#[test]
+fn test_from_result_fn_err() {
+    let released = std::cell::Cell::new(None);
+    {
+        let _d = Defer::from_result_fn(
+            || Err::<u32, _>("busy"),
+            |handle| released.set(Some(Ok(handle))),
+            |error| released.set(Some(Err(error))),
+        );
+    }
+    assert_eq!(released.get(), Some(Err("busy")));
+}
+
+#[test]
+fn test_new_send() {
+    fn assert_send<T: Send>(_: &T) {}
+
+    let ran_on = Arc::new(std::sync::Mutex::new(None));
+    let ran_on_clone = Arc::clone(&ran_on);
+    let d = Defer::new_send(move || {
+        *ran_on_clone.lock().unwrap() = Some(std::thread::current().id());
+    });
+    assert_send(&d);
+
+    let spawned = std::thread::spawn(move || {
+        drop(d);
+        std::thread::current().id()
+    })
+    .join()
+    .unwrap();
+
+    assert_ne!(spawned, std::thread::current().id());
+    assert_eq!(*ran_on.lock().unwrap(), Some(spawned));
+}
+
+#[test]
+fn test_scope_hook() {
+    use rdefer::{install_scope_hook, uninstall_scope_hook, RegisteredDefer};
+    use std::sync::Mutex;
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let panic_with_guards = |log: &Arc<Mutex<Vec<&'static str>>>| {
+        let log = Arc::clone(log);
+        let result = std::panic::catch_unwind(move || {
+            let _registered = RegisteredDefer::new({
+                let log = Arc::clone(&log);
+                move || log.lock().unwrap().push("registered")
+            });
+            let _plain = Defer::new(move || log.lock().unwrap().push("plain"));
+            panic!("scope hook test");
+        });
+        assert!(result.is_err());
+    };
+
+    // The hook runs the registered guard at the panic, before unwinding starts.
+    install_scope_hook();
+    panic_with_guards(&log);
+    assert_eq!(*log.lock().unwrap(), ["registered", "plain"]);
+
+    // Without the hook the guards simply run in drop order.
+    log.lock().unwrap().clear();
+    uninstall_scope_hook();
+    panic_with_guards(&log);
+    assert_eq!(*log.lock().unwrap(), ["plain", "registered"]);
+}
+
+#[test]
+fn test_defer_stack_run_tag() {
+    let log = std::cell::RefCell::new(Vec::new());
+    {
+        let mut stack = DeferStack::new();
+        stack.push_tagged("db", || log.borrow_mut().push("db 1"));
+        stack.push_tagged("file", || log.borrow_mut().push("file 1"));
+        stack.push_tagged("db", || log.borrow_mut().push("db 2"));
+        stack.push(|| log.borrow_mut().push("untagged"));
+
+        assert_eq!(stack.run_tag("db"), 2);
+        assert_eq!(*log.borrow(), ["db 2", "db 1"]);
+        assert_eq!(stack.len(), 2);
+    }
+    assert_eq!(*log.borrow(), ["db 2", "db 1", "untagged", "file 1"]);
+}
+
+#[test]
+fn test_if_elapsed_skips_below_threshold() {
+    let mut value = 0;
+    {
+        let _d = Defer::if_elapsed(Duration::from_secs(60), || value = 1);
+    }
+    assert_eq!(value, 0);
+}
+
+#[test]
+fn test_if_elapsed_runs_above_threshold() {
+    let mut value = 0;
+    {
+        let _d = Defer::if_elapsed(Duration::from_millis(10), || value = 1);
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(value, 1);
+}
+
+#[test]
+fn test_on_unwind_with() {
+    let seen = Arc::new(std::sync::Mutex::new(None));
+    {
+        let seen = Arc::clone(&seen);
+        let _d = Defer::on_unwind_with(move |_| *seen.lock().unwrap() = Some(String::new()));
+    }
+    assert_eq!(*seen.lock().unwrap(), None);
+
+    let seen_clone = Arc::clone(&seen);
+    let result = std::panic::catch_unwind(move || {
+        let _d = Defer::on_unwind_with(move |message| {
+            *seen_clone.lock().unwrap() = message.map(String::from);
+        });
+        panic!("disk on fire");
+    });
+    assert!(result.is_err());
+    assert_eq!(seen.lock().unwrap().as_deref(), Some("disk on fire"));
+}
+
+#[test]
+fn test_defer_stack_run_until_error() {
+    let log = std::cell::RefCell::new(Vec::new());
+    {
+        let mut stack = DeferStack::with_capacity_fallible(3);
+        assert!(stack.capacity() >= 3);
+        stack.push(|| log.borrow_mut().push("first"));
+        stack.push_try(|| {
+            log.borrow_mut().push("second");
+            Err("second failed")
+        });
+        stack.push_try(|| {
+            log.borrow_mut().push("third");
+            Ok(())
+        });
+
+        assert_eq!(stack.run_until_error(), Err("second failed"));
+        assert_eq!(*log.borrow(), ["third", "second"]);
+        assert_eq!(stack.len(), 1);
+        assert_eq!(stack.run_until_error(), Ok(1));
+    }
+    assert_eq!(*log.borrow(), ["third", "second", "first"]);
+}
+
+#[test]
+fn test_on_unwind_with_resume_unwind() {
+    use std::panic::{self, AssertUnwindSafe};
+
+    // Installs the hook, so the next panic's message is recorded.
+    drop(Defer::on_unwind_with(|_| {}));
+    assert!(panic::catch_unwind(|| panic!("old, already handled")).is_err());
+
+    let seen = std::sync::Mutex::new(Some(String::new()));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let _d = Defer::on_unwind_with(|message| {
+            *seen.lock().unwrap() = message.map(String::from);
+        });
+        panic::resume_unwind(Box::new("new failure"));
+    }));
+    assert!(result.is_err());
+    assert_eq!(*seen.lock().unwrap(), None);
+}
+
+#[test]
+fn test_defer_stack_extend() {
+    let log = std::cell::RefCell::new(Vec::new());
+    {
+        let mut stack = DeferStack::new();
+        stack.push(|| log.borrow_mut().push(0));
+
+        let mut fs: Vec<Box<dyn FnOnce()>> = Vec::new();
+        for i in 1..4 {
+            let log = &log;
+            fs.push(Box::new(move || log.borrow_mut().push(i)));
+        }
+        stack.extend(fs);
+
+        assert_eq!(stack.len(), 4);
+    }
+    assert_eq!(*log.borrow(), [3, 2, 1, 0]);
+}
+
+#[test]
+fn test_defer_stack_with_capacity() {
+    let log = std::cell::RefCell::new(Vec::new());
+    {
+        let mut stack = DeferStack::with_capacity(8);
+        assert!(stack.capacity() >= 8);
+        assert!(stack.is_empty());
+
+        for i in 0..8 {
+            let log = &log;
+            stack.push(move || log.borrow_mut().push(i));
+        }
+        assert_eq!(stack.len(), 8);
+        assert!(!stack.is_empty());
+    }
+    assert_eq!(*log.borrow(), [7, 6, 5, 4, 3, 2, 1, 0]);
+}
+
+#[test]
+fn test_fixed_defer_stack() {
+    let log = std::cell::RefCell::new(Vec::new());
+    let entry = |i| {
+        let log = &log;
+        move || log.borrow_mut().push(i)
+    };
+    {
+        let mut stack = FixedDeferStack::<_, 3>::new();
+        assert!(stack.is_empty());
+        for i in 0..3 {
+            assert!(stack.push(entry(i)).is_ok());
+        }
+        assert_eq!(stack.len(), 3);
+
+        let rejected = stack.push(entry(3));
+        assert!(rejected.is_err());
+        assert_eq!(stack.len(), 3);
+        assert!(log.borrow().is_empty());
+    }
+    assert_eq!(*log.borrow(), [2, 1, 0]);
+}
+
+#[test]
+fn test_with_cleanup() {
+    let log = std::cell::RefCell::new(Vec::new());
+    {
+        let mut items = rdefer::with_cleanup(vec!["a", "b", "c"], |item| {
+            let (log, item) = (&log, *item);
+            move || log.borrow_mut().push(format!("cleanup {}", item))
+        });
+        for _ in 0..2 {
+            let item = items.next().unwrap();
+            log.borrow_mut().push(item.to_string());
+        }
+        assert_eq!(*log.borrow(), ["a", "cleanup a", "b"]);
+    }
+    assert_eq!(*log.borrow(), ["a", "cleanup a", "b", "cleanup b"]);
+}
+
+#[test]
+fn test_defer_at_depth() {
+    use rdefer::{defer_at_depth, ScopeFrame};
+    use std::rc::Rc;
+
+    let log = Rc::new(std::cell::RefCell::new(Vec::new()));
+    {
+        let _outer = ScopeFrame::enter();
+        {
+            let _inner = ScopeFrame::enter();
+            let (outer_log, inner_log) = (Rc::clone(&log), Rc::clone(&log));
+            defer_at_depth!(1, { outer_log.borrow_mut().push("outer") });
+            defer_at_depth!(0, { inner_log.borrow_mut().push("inner") });
+            assert!(log.borrow().is_empty());
+        }
+        assert_eq!(*log.borrow(), ["inner"]);
+    }
+    assert_eq!(*log.borrow(), ["inner", "outer"]);
+}
+
+#[test]
+fn test_scope_frame_popped_by_outer_frame() {
+    use rdefer::{defer_at_depth, ScopeFrame};
+    use std::rc::Rc;
+
+    let log = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let outer = ScopeFrame::enter();
+    let inner = ScopeFrame::enter();
+    // Dropping the outer frame first pops the inner one along with it.
+    drop(outer);
+
+    let _a = ScopeFrame::enter();
+    let b = ScopeFrame::enter();
+    let b_log = Rc::clone(&log);
+    defer_at_depth!(0, { b_log.borrow_mut().push("b") });
+
+    // The inner frame is gone, so it must not pop `b`, which took its place.
+    drop(inner);
+    assert!(log.borrow().is_empty());
+    drop(b);
+    assert_eq!(*log.borrow(), ["b"]);
+}
+
+#[test]
+fn test_flush_defer_never_triggered() {
+    let mut flushes = 0;
+    {
+        let _d = FlushDefer::new(|| flushes += 1);
+    }
+    assert_eq!(flushes, 1);
+}
+
+#[test]
+fn test_flush_defer_triggered() {
+    let flushes = std::cell::Cell::new(0);
+    {
+        let mut d = FlushDefer::new(|| flushes.set(flushes.get() + 1));
+        d.trigger();
+        assert!(d.is_triggered());
+        d.trigger();
+        assert_eq!(flushes.get(), 2);
+    }
+    assert_eq!(flushes.get(), 2);
+}
+
+#[test]
+fn test_once_defer() {
+    let runs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let a = OnceDefer::new({
+        let runs = Arc::clone(&runs);
+        move || {
+            runs.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    let b = a.clone();
+
+    std::thread::spawn(move || b.trigger()).join().unwrap();
+    assert!(a.is_done());
+    a.trigger();
+    drop(a);
+
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_once_defer_panicking_trigger() {
+    use std::panic::{self, AssertUnwindSafe};
+
+    // The last owner is dropped while the panic unwinds.
+    let result = panic::catch_unwind(|| {
+        let d = OnceDefer::new(|| panic!("cleanup failed"));
+        d.trigger();
+    });
+    assert!(result.is_err());
+
+    // The last owner is dropped after the panic was caught.
+    let d = OnceDefer::new(|| panic!("cleanup failed"));
+    let d_clone = d.clone();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| d_clone.trigger()));
+    assert!(result.is_err());
+    d.trigger();
+    assert!(d.is_done());
+    drop(d);
+}
+
+#[test]
+fn test_once_defer_runs_on_last_drop() {
+    let runs = std::cell::Cell::new(0);
+    let a = OnceDefer::new(|| runs.set(runs.get() + 1));
+    let b = a.clone();
+
+    drop(a);
+    assert_eq!(runs.get(), 0);
+    drop(b);
+    assert_eq!(runs.get(), 1);
+}
+
+#[test]
+fn test_lazy_defer_initialized() {
+    let mut torn_down = None;
+    {
+        let mut d = LazyDefer::new(|value: Vec<i32>| torn_down = Some(value));
+        d.get_or_init(Vec::new).push(1);
+        d.get_or_init(|| unreachable!()).push(2);
+        assert!(d.is_initialized());
+    }
+    assert_eq!(torn_down, Some(vec![1, 2]));
+}
+
+#[test]
+fn test_lazy_defer_never_initialized() {
+    let mut torn_down = false;
+    {
+        let d = LazyDefer::new(|_: Vec<i32>| torn_down = true);
+        assert!(!d.is_initialized());
+    }
+    assert!(!torn_down);
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn test_trace_scope() {
+    use std::sync::Mutex;
+
+    struct CapturingLogger(Mutex<Vec<String>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+
+    {
+        let _d = Defer::trace_scope("load_config");
+        LOGGER.0.lock().unwrap().push("body".to_string());
+    }
+
+    let lines = LOGGER.0.lock().unwrap();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "enter load_config");
+    assert_eq!(lines[1], "body");
+    assert!(lines[2].starts_with("exit load_config after "));
+}
+
+#[cfg(feature = "anyhow")]
+#[test]
+fn test_new_anyhow() {
+    let (d, errors) = Defer::new_anyhow("flushing the cache", || anyhow::bail!("disk full"));
+    assert!(errors.take_error().is_none());
+
+    drop(d);
+
+    let error = errors.take_error().unwrap();
+    assert_eq!(error.to_string(), "flushing the cache");
+    assert_eq!(error.root_cause().to_string(), "disk full");
+    assert!(format!("{:#}", error).contains("flushing the cache: disk full"));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_defer_into_future() {
+    let ran = Arc::new(AtomicBool::new(false));
+    let d = Defer::new({
+        let ran = Arc::clone(&ran);
+        move || ran.store(true, Ordering::SeqCst)
+    });
+
+    let cleanup = d.into_future();
+    assert!(!ran.load(Ordering::SeqCst));
+
+    cleanup.await;
+    assert!(ran.load(Ordering::SeqCst));
+}
+
 #[cfg(feature = "async")]
 #[tokio::test]
 async fn test_async_defer() {
@@ -19,24 +860,27 @@ async fn test_async_defer() {
     let value = Arc::new(Mutex::new(0));
     let value_clone1 = Arc::clone(&value);
     let value_clone2 = Arc::clone(&value);
+    let value_clone3 = Arc::clone(&value);
 
-    let defer = async_defer!(2, async {
+    let defer = async_defer!(2, async move {
         // After the counter has been decremented twice, this will increment the value by 1.
-        let mut value = value.lock().unwrap();
+        let mut value = value_clone3.lock().unwrap();
         *value += 1;
     });
 
-    exec_before_defer!(defer, || {
+    exec_before_defer!(defer, move || {
         // This will increment the value by 1.
         let mut value = value_clone1.lock().unwrap();
         *value += 1;
-    });
+    })
+    .unwrap();
 
-    exec_before_defer!(defer, || {
+    exec_before_defer!(defer, move || {
         // This will increment the value by 1 again.
         let mut value = value_clone2.lock().unwrap();
         *value += 1;
-    });
+    })
+    .unwrap();
 
     // Sleep here to allow async tasks to finish
     tokio::time::sleep(std::time::Duration::from_secs(2)).await;
@@ -44,3 +888,578 @@ async fn test_async_defer() {
     // At this point, the value should be 3.
     assert_eq!(*value.lock().unwrap(), 3);
 }
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_from_counter_arc() {
+    use rdefer::async_defer::{tick, AsyncDefer};
+    use std::sync::Mutex;
+
+    let fired = Arc::new(AtomicBool::new(false));
+    let fired_clone = Arc::clone(&fired);
+    let counter = Arc::new(Mutex::new(2));
+
+    let _defer = AsyncDefer::from_counter_arc(Arc::clone(&counter), async move {
+        fired_clone.store(true, Ordering::SeqCst);
+    });
+
+    // Another subsystem counts the defer down without ever touching it.
+    tokio::spawn(async move {
+        tick(&counter);
+        tick(&counter);
+    })
+    .await
+    .unwrap();
+
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    assert!(fired.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_tick_past_zero_keeps_counter_usable() {
+    use rdefer::async_defer::tick;
+    use std::sync::Mutex;
+
+    let counter = Mutex::new(0);
+    assert!(std::panic::catch_unwind(|| tick(&counter)).is_err());
+    assert!(!counter.is_poisoned());
+    assert_eq!(*counter.lock().unwrap(), 0);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_defer_ui() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_exec_timeout() {
+    use rdefer::async_defer::AsyncDefer;
+
+    let fired = Arc::new(AtomicBool::new(false));
+    let fired_clone = Arc::clone(&fired);
+    let defer = AsyncDefer::new(2, async move {
+        fired_clone.store(true, Ordering::SeqCst);
+    });
+
+    defer
+        .lock()
+        .unwrap()
+        .exec_timeout(Duration::from_millis(50), || {
+            std::thread::sleep(Duration::from_millis(500))
+        })
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(defer.lock().unwrap().remaining(), 1);
+    assert_eq!(defer.lock().unwrap().timeouts(), 1);
+
+    defer
+        .lock()
+        .unwrap()
+        .exec_timeout(Duration::from_millis(500), || {})
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(defer.lock().unwrap().remaining(), 0);
+    assert_eq!(defer.lock().unwrap().timeouts(), 1);
+    assert!(fired.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_runtime_handle() {
+    use rdefer::async_defer::AsyncDefer;
+
+    let defer = AsyncDefer::new(1, async {});
+    let handle = defer.lock().unwrap().runtime_handle();
+
+    let task = handle.spawn(async { tokio::runtime::Handle::current().id() });
+
+    assert_eq!(task.await.unwrap(), handle.id());
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_async_fn() {
+    use rdefer::{async_defer, exec_before_defer};
+
+    async fn deferred(fired: Arc<AtomicBool>) {
+        // Holding a borrow across an await point makes this future self-referential.
+        let fired = &fired;
+        tokio::task::yield_now().await;
+        fired.store(true, Ordering::SeqCst);
+    }
+
+    let fired = Arc::new(AtomicBool::new(false));
+    let defer = async_defer!(1, deferred(Arc::clone(&fired)));
+
+    exec_before_defer!(defer, || {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert!(fired.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_exec_blocking() {
+    use rdefer::async_defer::AsyncDefer;
+
+    let fired = Arc::new(AtomicBool::new(false));
+    let fired_clone = Arc::clone(&fired);
+    let defer = AsyncDefer::new(1, async move {
+        fired_clone.store(true, Ordering::SeqCst);
+    });
+
+    defer
+        .lock()
+        .unwrap()
+        .exec_blocking(|| std::thread::sleep(Duration::from_millis(50)))
+        .unwrap();
+    assert_eq!(defer.lock().unwrap().remaining(), 1);
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(defer.lock().unwrap().remaining(), 0);
+    assert!(fired.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_fire_on() {
+    use rdefer::async_defer::AsyncDefer;
+    use std::sync::Mutex;
+
+    let ran_on = Arc::new(Mutex::new(None));
+    let ran_on_clone = Arc::clone(&ran_on);
+    let defer = AsyncDefer::new(1, async move {
+        *ran_on_clone.lock().unwrap() = Some(tokio::runtime::Handle::current().id());
+    });
+    let actions_runtime = defer.lock().unwrap().runtime_handle().id();
+    let cleanup_runtime = tokio::runtime::Handle::current();
+
+    defer.lock().unwrap().fire_on(cleanup_runtime.clone());
+    defer.lock().unwrap().exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let ran_on = ran_on.lock().unwrap().unwrap();
+    assert_eq!(ran_on, cleanup_runtime.id());
+    assert_ne!(ran_on, actions_runtime);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_map_future() {
+    use rdefer::async_defer::AsyncDefer;
+    use std::sync::Mutex;
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let log_clone = Arc::clone(&log);
+    let defer = AsyncDefer::new(2, async move { log_clone.lock().unwrap().push("deferred") });
+    defer.lock().unwrap().exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let defer = Arc::try_unwrap(defer).ok().unwrap().into_inner().unwrap();
+    let log_clone = Arc::clone(&log);
+    let mut defer = defer.map_future(|f| async move {
+        log_clone.lock().unwrap().push("before");
+        f.await;
+        log_clone.lock().unwrap().push("after");
+    });
+    assert_eq!(defer.remaining(), 1);
+
+    defer.exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(*log.lock().unwrap(), ["before", "deferred", "after"]);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_exec_token() {
+    use rdefer::async_defer::AsyncDefer;
+
+    let fired = Arc::new(AtomicBool::new(false));
+    let fired_clone = Arc::clone(&fired);
+    let defer = AsyncDefer::new(0, async move {
+        fired_clone.store(true, Ordering::SeqCst);
+    });
+
+    let tokens: Vec<_> = (0..3).map(|_| defer.lock().unwrap().exec_token()).collect();
+    assert_eq!(defer.lock().unwrap().remaining(), 3);
+
+    let mut tokens = tokens.into_iter();
+    drop(tokens.next());
+    drop(tokens.next());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(defer.lock().unwrap().remaining(), 1);
+    assert!(!fired.load(Ordering::SeqCst));
+
+    drop(tokens);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(defer.lock().unwrap().remaining(), 0);
+    assert!(fired.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_detach() {
+    use rdefer::async_defer::AsyncDefer;
+
+    let fired = Arc::new(AtomicBool::new(false));
+    let fired_clone = Arc::clone(&fired);
+    let defer = AsyncDefer::new(2, async move {
+        fired_clone.store(true, Ordering::SeqCst);
+    });
+
+    let exec = defer.lock().unwrap().detach();
+    drop(defer);
+
+    exec.exec(|| {}).unwrap();
+    exec.clone().exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert!(fired.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_fire_at() {
+    use rdefer::async_defer::AsyncDefer;
+
+    let fired = Arc::new(AtomicBool::new(false));
+    let fired_clone = Arc::clone(&fired);
+    let defer = AsyncDefer::new(3, async move {
+        fired_clone.store(true, Ordering::SeqCst);
+    });
+    defer.lock().unwrap().fire_at(1);
+
+    defer.lock().unwrap().exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!fired.load(Ordering::SeqCst));
+
+    defer.lock().unwrap().exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(defer.lock().unwrap().remaining(), 1);
+    assert!(fired.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_into_parts() {
+    use rdefer::async_defer::AsyncDefer;
+
+    let fired = Arc::new(AtomicBool::new(false));
+    let fired_clone = Arc::clone(&fired);
+    let defer = AsyncDefer::new(3, async move {
+        fired_clone.store(true, Ordering::SeqCst);
+    });
+    let defer = Arc::try_unwrap(defer).ok().unwrap().into_inner().unwrap();
+
+    let (counter, completion) = defer.into_parts();
+    loop {
+        let remaining = {
+            let mut counter = counter.lock().unwrap();
+            *counter -= 1;
+            *counter
+        };
+        assert!(!fired.load(Ordering::SeqCst));
+        if remaining == 0 {
+            let _ = completion.fire().await;
+            break;
+        }
+    }
+
+    assert!(fired.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_with_shutdown() {
+    use rdefer::async_defer::{AsyncDefer, CleanupOutcome};
+
+    let completed = Arc::new(AtomicBool::new(false));
+    let completed_clone = Arc::clone(&completed);
+    let defer = AsyncDefer::with_shutdown(1, async {}, async move {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        completed_clone.store(true, Ordering::SeqCst);
+    });
+    assert_eq!(defer.lock().unwrap().outcome(), None);
+
+    defer.lock().unwrap().exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(
+        defer.lock().unwrap().outcome(),
+        Some(CleanupOutcome::ShutDown)
+    );
+    assert!(!completed.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_outcome_completed() {
+    use rdefer::async_defer::{AsyncDefer, CleanupOutcome};
+
+    let defer = AsyncDefer::new(1, async {});
+    defer.lock().unwrap().exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(
+        defer.lock().unwrap().outcome(),
+        Some(CleanupOutcome::Completed)
+    );
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_policy_panic() {
+    use rdefer::async_defer::{AsyncDefer, CounterPolicy};
+
+    let defer = AsyncDefer::new_with_policy(1, CounterPolicy::Panic, async {});
+    defer.lock().unwrap().exec(|| {}).unwrap();
+
+    let mut defer = defer.lock().unwrap();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| defer.exec(|| {})));
+    assert!(result.is_err());
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_policy_saturate() {
+    use rdefer::async_defer::AsyncDefer;
+    use std::sync::atomic::AtomicUsize;
+
+    let fired = Arc::new(AtomicUsize::new(0));
+    let ran = Arc::new(AtomicUsize::new(0));
+    let fired_clone = Arc::clone(&fired);
+    let defer = AsyncDefer::new(1, async move {
+        fired_clone.fetch_add(1, Ordering::SeqCst);
+    });
+
+    for _ in 0..3 {
+        let ran = Arc::clone(&ran);
+        defer
+            .lock()
+            .unwrap()
+            .exec(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(defer.lock().unwrap().remaining(), 0);
+    assert_eq!(ran.load(Ordering::SeqCst), 3);
+    assert_eq!(fired.load(Ordering::SeqCst), 1);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_policy_error() {
+    use rdefer::async_defer::{AsyncDefer, CounterExhausted, CounterPolicy};
+
+    let ran = Arc::new(AtomicBool::new(false));
+    let ran_clone = Arc::clone(&ran);
+    let defer = AsyncDefer::new_with_policy(1, CounterPolicy::Error, async {});
+    defer.lock().unwrap().exec(|| {}).unwrap();
+
+    let result = defer
+        .lock()
+        .unwrap()
+        .exec(move || ran_clone.store(true, Ordering::SeqCst));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(result, Err(CounterExhausted));
+    assert!(!ran.load(Ordering::SeqCst));
+    assert_eq!(defer.lock().unwrap().remaining(), 0);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_rearm() {
+    use rdefer::async_defer::AsyncDefer;
+    use std::sync::atomic::AtomicUsize;
+
+    async fn deferred(fired: Arc<AtomicUsize>, round: usize) {
+        fired.store(round, Ordering::SeqCst);
+    }
+
+    let fired = Arc::new(AtomicUsize::new(0));
+    let defer = AsyncDefer::new(1, deferred(Arc::clone(&fired), 1));
+    defer.lock().unwrap().exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(fired.load(Ordering::SeqCst), 1);
+
+    defer
+        .lock()
+        .unwrap()
+        .rearm(2, deferred(Arc::clone(&fired), 2));
+    assert_eq!(defer.lock().unwrap().remaining(), 2);
+    assert_eq!(defer.lock().unwrap().outcome(), None);
+
+    defer.lock().unwrap().exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(fired.load(Ordering::SeqCst), 1);
+
+    defer.lock().unwrap().exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(fired.load(Ordering::SeqCst), 2);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_exec_before_defer_closure_and_async_block() {
+    use rdefer::{async_defer, exec_before_defer};
+
+    let fired = Arc::new(AtomicBool::new(false));
+    let fired_clone = Arc::clone(&fired);
+    let defer = async_defer!(2, async move {
+        fired_clone.store(true, Ordering::SeqCst);
+    });
+
+    exec_before_defer!(defer, || {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(defer.lock().unwrap().remaining(), 1);
+
+    exec_before_defer!(defer, async {
+        tokio::task::yield_now().await;
+    })
+    .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(defer.lock().unwrap().remaining(), 0);
+    assert!(fired.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "test-util")]
+#[test]
+fn test_async_defer_block_until_fired() {
+    use rdefer::{async_defer, exec_before_defer};
+
+    let fired = Arc::new(AtomicBool::new(false));
+    let fired_clone = Arc::clone(&fired);
+    let defer = async_defer!(2, async move {
+        tokio::task::yield_now().await;
+        fired_clone.store(true, Ordering::SeqCst);
+    });
+
+    exec_before_defer!(defer, || {}).unwrap();
+    exec_before_defer!(defer, || {}).unwrap();
+    defer.lock().unwrap().block_until_fired();
+    assert!(fired.load(Ordering::SeqCst));
+
+    // Once fired, it returns right away.
+    defer.lock().unwrap().block_until_fired();
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_with_completion_counter() {
+    use rdefer::async_defer::AsyncDefer;
+    use std::sync::atomic::AtomicUsize;
+
+    let completions = Arc::new(AtomicUsize::new(0));
+    let defer = AsyncDefer::with_completion_counter(1, Arc::clone(&completions), async {
+        tokio::task::yield_now().await;
+    });
+    assert_eq!(completions.load(Ordering::SeqCst), 0);
+
+    defer.lock().unwrap().exec(|| {}).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while completions.load(Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(completions.load(Ordering::SeqCst), 1);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_defer_drop_while_firing() {
+    use rdefer::async_defer::AsyncDefer;
+    use std::thread;
+
+    for i in 0..100 {
+        let started = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+        let (started_clone, finished_clone) = (Arc::clone(&started), Arc::clone(&finished));
+        let defer = AsyncDefer::new(1, async move {
+            started_clone.store(true, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            finished_clone.store(true, Ordering::SeqCst);
+        });
+
+        defer.lock().unwrap().exec(|| {}).unwrap();
+        // Spread the drops around the moment the counter reaches 0.
+        thread::sleep(Duration::from_micros(i % 20 * 10));
+        drop(defer);
+
+        thread::sleep(Duration::from_millis(20));
+        // Either the deferred function never started, or it ran to completion.
+        assert_eq!(
+            started.load(Ordering::SeqCst),
+            finished.load(Ordering::SeqCst)
+        );
+    }
+}
+
+#[cfg(all(feature = "async", feature = "serde"))]
+#[tokio::test]
+async fn test_async_defer_snapshot_state() {
+    use rdefer::async_defer::AsyncDefer;
+
+    let defer = AsyncDefer::new(2, async {});
+    defer.lock().unwrap().exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let snapshot = defer.lock().unwrap().snapshot_state();
+    assert_eq!(
+        serde_json::to_value(&snapshot).unwrap(),
+        serde_json::json!({ "remaining": 1, "fired": false, "exec_calls": 1 })
+    );
+
+    defer.lock().unwrap().exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let snapshot = defer.lock().unwrap().snapshot_state();
+    assert_eq!(
+        serde_json::to_value(&snapshot).unwrap(),
+        serde_json::json!({ "remaining": 0, "fired": true, "exec_calls": 2 })
+    );
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_drop_after_panicking_fire() {
+    use rdefer::async_defer::AsyncDefer;
+
+    let defer = AsyncDefer::new(1, async { panic!("cleanup failed") });
+    defer.lock().unwrap().exec(|| {}).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(defer.lock().unwrap().outcome(), None);
+    // Dropping the runtime normally would panic within asynchronous code.
+    drop(defer);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_defer_drop_unfired_detached() {
+    use rdefer::async_defer::AsyncDefer;
+
+    let defer = AsyncDefer::new(1, async {});
+    let handle = defer.lock().unwrap().detach();
+    drop(defer);
+    // The last reference takes the runtime down, which mustn't block here.
+    drop(handle);
+
+    let defer = AsyncDefer::new(1, async {});
+    let defer = Arc::try_unwrap(defer).ok().unwrap().into_inner().unwrap();
+    let (_counter, completion) = defer.into_parts();
+    drop(completion);
+}
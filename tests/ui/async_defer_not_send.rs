@@ -0,0 +1,10 @@
+use rdefer::async_defer;
+use std::rc::Rc;
+
+fn main() {
+    let value = Rc::new(0);
+
+    let _defer = async_defer!(1, async move {
+        println!("{}", value);
+    });
+}